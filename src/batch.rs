@@ -0,0 +1,91 @@
+//! Summarizing many texts in one call.
+//!
+//! Requests for the texts of a batch are sent concurrently, up to
+//! `pg_summarizer.batch_parallelism` at a time, on the backend's HTTP runtime.
+//! Each text succeeds or fails on its own, and results keep the order of the
+//! input array.
+
+use crate::error::SummarizeError;
+use crate::{guc, Summarizer};
+use pgrx::prelude::*;
+
+/// Summarizes every element of `inputs`; NULL elements stay NULL. Failed
+/// elements are handled according to `on_error`, as in `summarize()`, so with
+/// the default mode the first failure is raised after the batch has run.
+#[pg_extern]
+fn summarize_batch(
+    inputs: Vec<Option<String>>,
+    on_error: default!(Option<&str>, "NULL"),
+    parallelism: default!(Option<i32>, "NULL"),
+) -> Vec<Option<String>> {
+    let on_error = crate::on_error_mode(on_error);
+    summarize_each(&inputs, parallelism)
+        .into_iter()
+        .map(|result| match result? {
+            Ok(summary) => Some(summary),
+            Err(e) => crate::recover(e, on_error),
+        })
+        .collect()
+}
+
+/// Like `summarize_batch()`, but returns a row per element with its position
+/// in `inputs`, and the message and SQLSTATE of its error instead of raising
+/// it.
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn summarize_batch_rows(
+    inputs: Vec<Option<String>>,
+    parallelism: default!(Option<i32>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(ordinality, i64),
+        name!(summary, Option<String>),
+        name!(error, Option<String>),
+        name!(sqlstate, Option<String>),
+    ),
+> {
+    let rows = summarize_each(&inputs, parallelism)
+        .into_iter()
+        .zip(1..)
+        .map(|(result, ordinality)| match result {
+            None => (ordinality, None, None, None),
+            Some(Ok(summary)) => (ordinality, Some(summary), None, None),
+            Some(Err(e)) => (ordinality, None, Some(e.to_string()), Some(e.sqlstate())),
+        });
+    TableIterator::new(rows.collect::<Vec<_>>())
+}
+
+/// The result for each element of `inputs`, None for NULL elements.
+fn summarize_each(
+    inputs: &[Option<String>],
+    parallelism: Option<i32>,
+) -> Vec<Option<Result<String, SummarizeError>>> {
+    let parallelism = parallelism.unwrap_or_else(|| guc::BATCH_PARALLELISM.get());
+    if !(1..=guc::MAX_BATCH_PARALLELISM).contains(&parallelism) {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "parallelism must be between 1 and {}, not {}",
+                guc::MAX_BATCH_PARALLELISM,
+                parallelism
+            )
+        );
+    }
+
+    let texts: Vec<&str> = inputs.iter().flatten().map(String::as_str).collect();
+    let results = match Summarizer::configured(None, None) {
+        Ok(summarizer) => summarizer.summarize_all(&texts, parallelism as usize),
+        Err(e) => texts.iter().map(|_| Err(e.clone())).collect(),
+    };
+    let mut results = results.into_iter();
+    inputs
+        .iter()
+        .map(|input| {
+            input
+                .as_ref()
+                .map(|_| results.next().expect("one result per text"))
+        })
+        .collect()
+}
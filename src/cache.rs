@@ -0,0 +1,230 @@
+//! Opt-in cache of summaries in the `summarize_cache` table.
+//!
+//! Entries are keyed by a SHA-256 hash of everything that determines the
+//! answer: the provider, its endpoint and the request body it would be sent,
+//! built from the input with whitespace normalized. The body carries the
+//! model, the prompt and any generation parameters, so changing one of those
+//! never returns a stale summary.
+
+use crate::guc;
+use crate::providers::{Provider, SummaryRequest};
+use crate::shared_cache::SHARED_CACHE;
+use pgrx::prelude::*;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+
+extension_sql!(
+    r#"
+CREATE TABLE summarize_cache (
+    key bytea PRIMARY KEY,
+    provider text NOT NULL,
+    model text NOT NULL,
+    summary text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_used_at timestamptz NOT NULL DEFAULT now(),
+    hits bigint NOT NULL DEFAULT 0
+);
+CREATE INDEX summarize_cache_last_used_at ON summarize_cache (last_used_at);
+CREATE INDEX summarize_cache_created_at ON summarize_cache (created_at);
+COMMENT ON TABLE summarize_cache IS 'Summaries cached by summarize() when pg_summarizer.cache is on';
+"#,
+    name = "summarize_cache",
+);
+
+/// Stores per session between two evictions; the first store evicts too.
+const EVICT_INTERVAL: i64 = 100;
+
+thread_local! {
+    static HITS: Cell<i64> = const { Cell::new(0) };
+    static MISSES: Cell<i64> = const { Cell::new(0) };
+    static STORES: Cell<i64> = const { Cell::new(0) };
+}
+
+pub fn enabled() -> bool {
+    guc::CACHE.get()
+}
+
+/// The cache key of `request` as `provider` would send it.
+pub fn key(provider: &dyn Provider, provider_name: &str, request: &SummaryRequest) -> [u8; 32] {
+    let input = normalize(request.input);
+    let normalized = SummaryRequest {
+        input: &input,
+        ..*request
+    };
+    let mut hasher = Sha256::new();
+    for field in [
+        provider_name.as_bytes(),
+        provider.endpoint(&normalized).as_bytes(),
+        provider.request_body(&normalized).to_string().as_bytes(),
+    ] {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field);
+    }
+    hasher.finalize().into()
+}
+
+/// Trims the input and collapses each run of whitespace into one space, so
+/// that reformatted copies of a text share an entry.
+fn normalize(input: &str) -> String {
+    input.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Looks up each of `keys`, as [`lookup`] does. Hits served from shared
+/// memory are then counted in the table too, in one statement, so that its
+/// least recently used entries are the ones evicted.
+pub fn lookup_all(keys: &[Option<[u8; 32]>]) -> Vec<Option<String>> {
+    let mut shared_hits = Vec::new();
+    let summaries = keys
+        .iter()
+        .map(|key| {
+            let key = key.as_ref()?;
+            let (summary, shared) = lookup(key)?;
+            if shared {
+                shared_hits.push(key.to_vec());
+            }
+            Some(summary)
+        })
+        .collect();
+    if !shared_hits.is_empty() && !read_only() {
+        Spi::run_with_args(
+            "UPDATE summarize_cache AS c SET hits = c.hits + h.count, last_used_at = now() \
+            FROM (SELECT key, count(*) AS count FROM unnest($1::bytea[]) AS key GROUP BY key) AS h \
+            WHERE c.key = h.key",
+            Some(vec![(
+                PgBuiltInOids::BYTEAARRAYOID.oid(),
+                shared_hits.into_datum(),
+            )]),
+        )
+        .unwrap_or_else(|e| error!("could not write summarize_cache: {}", e));
+    }
+    summaries
+}
+
+/// Returns the cached summary for `key` unless it is missing or has expired,
+/// looking in shared memory before the table, and whether it came from
+/// shared memory.
+fn lookup(key: &[u8; 32]) -> Option<(String, bool)> {
+    let ttl = guc::CACHE_TTL.get();
+    if let Some(summary) = SHARED_CACHE.lookup(key, ttl as u64) {
+        HITS.with(|count| count.set(count.get() + 1));
+        return Some((summary, true));
+    }
+    let args = vec![
+        (PgBuiltInOids::BYTEAOID.oid(), key.as_slice().into_datum()),
+        (PgBuiltInOids::INT4OID.oid(), ttl.into_datum()),
+    ];
+    let query = if read_only() {
+        "SELECT (SELECT summary FROM summarize_cache \
+        WHERE key = $1 AND ($2 = 0 OR created_at > now() - make_interval(secs => $2)))"
+    } else {
+        "WITH hit AS (UPDATE summarize_cache SET hits = hits + 1, last_used_at = now() \
+        WHERE key = $1 AND ($2 = 0 OR created_at > now() - make_interval(secs => $2)) \
+        RETURNING summary) SELECT (SELECT summary FROM hit)"
+    };
+    let summary = Spi::get_one_with_args::<String>(query, args).unwrap_or_else(|e| {
+        error!("could not read summarize_cache: {}", e);
+    });
+    let counter = if summary.is_some() { &HITS } else { &MISSES };
+    counter.with(|count| count.set(count.get() + 1));
+    if let Some(summary) = &summary {
+        SHARED_CACHE.store(key, summary);
+    }
+    summary.map(|summary| (summary, false))
+}
+
+/// Stores `summary` under `key`. Every [`EVICT_INTERVAL`] stores, expired
+/// entries are evicted too and, beyond `pg_summarizer.cache_max_entries`, the
+/// least recently used ones.
+pub fn store(key: &[u8; 32], provider_name: &str, model: &str, summary: &str) {
+    SHARED_CACHE.store(key, summary);
+    if read_only() {
+        return;
+    }
+    Spi::run_with_args(
+        "INSERT INTO summarize_cache (key, provider, model, summary) VALUES ($1, $2, $3, $4) \
+        ON CONFLICT (key) DO UPDATE SET summary = EXCLUDED.summary, \
+        created_at = now(), last_used_at = now()",
+        Some(vec![
+            (PgBuiltInOids::BYTEAOID.oid(), key.as_slice().into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), provider_name.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), model.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), summary.into_datum()),
+        ]),
+    )
+    .and_then(|()| {
+        let stores = STORES.with(|count| count.replace(count.get() + 1));
+        if stores % EVICT_INTERVAL != 0 {
+            return Ok(());
+        }
+        Spi::run_with_args(
+            "DELETE FROM summarize_cache \
+            WHERE ($1 > 0 AND created_at <= now() - make_interval(secs => $1)) \
+            OR ($2 > 0 AND key IN (SELECT key FROM summarize_cache \
+            ORDER BY last_used_at DESC OFFSET $2))",
+            Some(vec![
+                (
+                    PgBuiltInOids::INT4OID.oid(),
+                    guc::CACHE_TTL.get().into_datum(),
+                ),
+                (
+                    PgBuiltInOids::INT4OID.oid(),
+                    guc::CACHE_MAX_ENTRIES.get().into_datum(),
+                ),
+            ]),
+        )
+    })
+    .unwrap_or_else(|e| error!("could not write summarize_cache: {}", e));
+}
+
+/// Whether the cache can only be read, e.g. on a standby.
+fn read_only() -> bool {
+    unsafe { pg_sys::XactReadOnly || pg_sys::RecoveryInProgress() }
+}
+
+/// Deletes cached summaries, only the expired ones if `expired_only` is set,
+/// and returns how many were deleted from the table. Purging everything also
+/// empties the shared-memory cache, whose expired entries are never served.
+#[pg_extern]
+fn summarize_cache_purge(expired_only: default!(bool, false)) -> i64 {
+    if !expired_only {
+        SHARED_CACHE.clear();
+    }
+    Spi::get_one_with_args::<i64>(
+        "WITH purged AS (DELETE FROM summarize_cache WHERE NOT $1 \
+        OR ($2 > 0 AND created_at <= now() - make_interval(secs => $2)) RETURNING 1) \
+        SELECT count(*) FROM purged",
+        vec![
+            (PgBuiltInOids::BOOLOID.oid(), expired_only.into_datum()),
+            (
+                PgBuiltInOids::INT4OID.oid(),
+                guc::CACHE_TTL.get().into_datum(),
+            ),
+        ],
+    )
+    .unwrap_or_else(|e| error!("could not purge summarize_cache: {}", e))
+    .unwrap_or_default()
+}
+
+/// Reports the number of cached summaries, the hits they have served in
+/// total, and the hits and misses of the current session.
+#[pg_extern]
+fn summarize_cache_stats() -> TableIterator<
+    'static,
+    (
+        name!(entries, i64),
+        name!(total_hits, i64),
+        name!(session_hits, i64),
+        name!(session_misses, i64),
+    ),
+> {
+    let (entries, total_hits) = Spi::get_two::<i64, i64>(
+        "SELECT count(*), coalesce(sum(hits), 0)::bigint FROM summarize_cache",
+    )
+    .unwrap_or_else(|e| error!("could not read summarize_cache: {}", e));
+    TableIterator::once((
+        entries.unwrap_or_default(),
+        total_hits.unwrap_or_default(),
+        HITS.with(Cell::get),
+        MISSES.with(Cell::get),
+    ))
+}
@@ -0,0 +1,252 @@
+//! Errors raised by `summarize()`.
+//!
+//! Each kind of failure maps to its own SQLSTATE so callers can handle it in a
+//! PL/pgSQL `EXCEPTION` block, e.g. `WHEN configuration_limit_exceeded` to
+//! back off after hitting a provider's rate limit.
+
+use pgrx::{PgLogLevel, PgSqlErrorCode, PostgresGucEnum};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// No API key or other credentials are configured for the provider.
+    MissingCredentials,
+    /// A setting holds a value the provider cannot work with.
+    InvalidConfiguration,
+    /// The provider answered with an error status.
+    Status,
+    /// The provider is throttling requests (HTTP 429).
+    RateLimited,
+    /// The request did not complete in time.
+    Timeout,
+    /// The provider could not be reached.
+    Connection,
+    /// The response could not be understood.
+    MalformedResponse,
+    /// The provider declined to produce a summary, e.g. because of a safety filter.
+    Refused,
+    /// The input does not fit the model's context window or
+    /// `pg_summarizer.max_input_tokens`.
+    InputTooLong,
+}
+
+impl ErrorKind {
+    pub fn sql_error_code(self) -> PgSqlErrorCode {
+        match self {
+            ErrorKind::MissingCredentials => {
+                PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION
+            }
+            ErrorKind::InvalidConfiguration => PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            ErrorKind::Status => PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_INVOCATION_EXCEPTION,
+            ErrorKind::RateLimited => PgSqlErrorCode::ERRCODE_CONFIGURATION_LIMIT_EXCEEDED,
+            ErrorKind::Timeout => PgSqlErrorCode::ERRCODE_CONNECTION_FAILURE,
+            ErrorKind::Connection => {
+                PgSqlErrorCode::ERRCODE_SQLCLIENT_UNABLE_TO_ESTABLISH_SQLCONNECTION
+            }
+            ErrorKind::MalformedResponse => PgSqlErrorCode::ERRCODE_PROTOCOL_VIOLATION,
+            ErrorKind::Refused => PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION,
+            ErrorKind::InputTooLong => PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+        }
+    }
+
+    fn hint(self) -> &'static str {
+        match self {
+            ErrorKind::MissingCredentials => {
+                "Set pg_summarizer.api_key, e.g. with ALTER SYSTEM, or pick a provider that needs no key."
+            }
+            ErrorKind::InvalidConfiguration => "Check the pg_summarizer.* settings.",
+            ErrorKind::Status => {
+                "Check the API key, the model name and pg_summarizer.base_url."
+            }
+            ErrorKind::RateLimited => "Lower the request rate or retry later.",
+            ErrorKind::Timeout => "The provider did not respond in time; retry later.",
+            ErrorKind::Connection => {
+                "Check pg_summarizer.base_url and that the provider is reachable from the database server."
+            }
+            ErrorKind::MalformedResponse => {
+                "Check that pg_summarizer.provider matches the API served at pg_summarizer.base_url."
+            }
+            ErrorKind::Refused => {
+                "The provider declined this input; see DETAIL for its reason."
+            }
+            ErrorKind::InputTooLong => {
+                "Use a model with a larger context window, or set pg_summarizer.long_text_strategy to truncate, map_reduce or refine."
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SummarizeError {
+    kind: ErrorKind,
+    message: String,
+    detail: Option<String>,
+    hint: Option<String>,
+    retryable: bool,
+    retry_after: Option<Duration>,
+}
+
+impl SummarizeError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        SummarizeError {
+            kind,
+            message: message.into(),
+            detail: None,
+            hint: None,
+            retryable: false,
+            retry_after: None,
+        }
+    }
+
+    /// Error for a response body that lacks the fields a summary is read from.
+    pub fn unexpected_format() -> Self {
+        SummarizeError::new(
+            ErrorKind::MalformedResponse,
+            "unexpected response format from provider",
+        )
+    }
+
+    /// Attaches the provider's response body, or any other detail, as DETAIL.
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Replaces the generic HINT of this kind of error.
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Marks this error as transient, so the call is retried, optionally after
+    /// the delay the provider asked for.
+    pub fn retryable(mut self, retry_after: Option<Duration>) -> Self {
+        self.retryable = true;
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// The five-character SQLSTATE this error is raised with.
+    pub fn sqlstate(&self) -> String {
+        let code = self.kind.sql_error_code() as u32;
+        (0..5)
+            .map(|i| char::from(b'0' + ((code >> (6 * i)) & 0x3F) as u8))
+            .collect()
+    }
+
+    /// Raises this error through `ereport`; does not return.
+    pub fn report(self) -> ! {
+        self.ereport(PgLogLevel::ERROR);
+        unreachable!()
+    }
+
+    /// Emits this error as a `WARNING`, with the same SQLSTATE, DETAIL and HINT.
+    pub fn warn(self) {
+        self.ereport(PgLogLevel::WARNING);
+    }
+
+    fn ereport(self, level: PgLogLevel) {
+        let hint = self.hint.unwrap_or_else(|| self.kind.hint().to_string());
+        let mut report = pgrx::pg_sys::panic::ErrorReport::new(
+            self.kind.sql_error_code(),
+            self.message,
+            "summarize",
+        )
+        .set_hint(hint);
+        if let Some(detail) = self.detail {
+            report = report.set_detail(detail);
+        }
+        report.report(level);
+    }
+}
+
+/// What `summarize()` does when the provider call fails, set by
+/// `pg_summarizer.on_error` or its `on_error` argument.
+#[allow(non_camel_case_types)]
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Debug)]
+pub enum OnError {
+    /// Raise the error, aborting the statement.
+    error,
+    /// Return NULL.
+    null,
+    /// Emit the error as a WARNING and return NULL.
+    warning,
+    /// Return `pg_summarizer.on_error_placeholder` instead of a summary.
+    placeholder,
+}
+
+impl OnError {
+    /// Parses the `on_error` argument of `summarize()`, case-insensitively
+    /// like the setting itself.
+    pub fn parse(value: &str) -> Result<Self, SummarizeError> {
+        match value.to_ascii_lowercase().as_str() {
+            "error" => Ok(OnError::error),
+            "null" => Ok(OnError::null),
+            "warning" => Ok(OnError::warning),
+            "placeholder" => Ok(OnError::placeholder),
+            _ => Err(SummarizeError::new(
+                ErrorKind::InvalidConfiguration,
+                format!("invalid on_error mode \"{}\"", value),
+            )
+            .with_hint("Use one of error, null, warning or placeholder.")),
+        }
+    }
+}
+
+impl fmt::Display for SummarizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for SummarizeError {}
+
+impl From<reqwest::Error> for SummarizeError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_builder() {
+            SummarizeError::new(
+                ErrorKind::InvalidConfiguration,
+                "could not build request to provider",
+            )
+            .with_detail(e.to_string())
+        } else if e.is_timeout() {
+            SummarizeError::new(ErrorKind::Timeout, "request to provider timed out")
+                .with_detail(e.to_string())
+                .retryable(None)
+        } else {
+            SummarizeError::new(
+                ErrorKind::Connection,
+                "could not complete request to provider",
+            )
+            .with_detail(e.to_string())
+            .retryable(None)
+        }
+    }
+}
+
+impl From<serde_json::Error> for SummarizeError {
+    fn from(e: serde_json::Error) -> Self {
+        SummarizeError::new(
+            ErrorKind::MalformedResponse,
+            format!("provider returned invalid JSON: {}", e),
+        )
+    }
+}
+
+impl From<reqwest::header::InvalidHeaderValue> for SummarizeError {
+    fn from(_: reqwest::header::InvalidHeaderValue) -> Self {
+        SummarizeError::new(
+            ErrorKind::InvalidConfiguration,
+            "credentials contain characters that are not allowed in an HTTP header",
+        )
+    }
+}
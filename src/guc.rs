@@ -0,0 +1,863 @@
+//! Settings registered under the `pg_summarizer` prefix.
+
+use crate::error::OnError;
+use crate::language::{LanguageCheck, OutputLanguage};
+use crate::long_text::LongTextStrategy;
+use crate::params;
+use crate::providers::ProviderKind;
+use crate::style;
+use pgrx::{pg_guard, pg_sys, GucContext, GucFlags, GucRegistry, GucSetting, PgMemoryContexts};
+use reqwest::Url;
+use std::cell::UnsafeCell;
+use std::ffi::{c_char, c_void, CStr};
+
+pub const DEFAULT_PROMPT: &CStr = c"You are an AI summarizing tool. \
+    Your purpose is to summarize the <text> tag, \
+    not to engage in conversation or discussion. \
+    Please read the <text> carefully. \
+    Then, summarize the key points. \
+    Focus on capturing the most important information as concisely as possible.";
+
+/// Values below the valid range of a generation parameter, which leave it
+/// unset.
+pub const UNSET_TEMPERATURE: f64 = -1.0;
+pub const UNSET_TOP_P: f64 = -1.0;
+pub const UNSET_PENALTY: f64 = -3.0;
+pub const UNSET_SEED: i32 = -1;
+
+pub static API_KEY: StringGuc = StringGuc::new(None);
+pub static MODEL: StringGuc = StringGuc::new(None);
+pub static PROMPT: StringGuc = StringGuc::new(Some(DEFAULT_PROMPT));
+pub static TEMPERATURE: GucSetting<f64> = GucSetting::<f64>::new(UNSET_TEMPERATURE);
+pub static MAX_TOKENS: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static TOP_P: GucSetting<f64> = GucSetting::<f64>::new(UNSET_TOP_P);
+pub static PRESENCE_PENALTY: GucSetting<f64> = GucSetting::<f64>::new(UNSET_PENALTY);
+pub static FREQUENCY_PENALTY: GucSetting<f64> = GucSetting::<f64>::new(UNSET_PENALTY);
+pub static SEED: GucSetting<i32> = GucSetting::<i32>::new(UNSET_SEED);
+pub static STOP: StringGuc = StringGuc::new(None);
+pub static LENGTH: StringGuc = StringGuc::new(None);
+pub static FORMAT: StringGuc = StringGuc::new(None);
+pub static AUDIENCE: StringGuc = StringGuc::new(None);
+pub static TONE: StringGuc = StringGuc::new(None);
+pub static OUTPUT_LANGUAGE: StringGuc = StringGuc::new(None);
+pub static LANGUAGE_CHECK: GucSetting<LanguageCheck> =
+    GucSetting::<LanguageCheck>::new(LanguageCheck::retry);
+pub static DEFAULT_PROFILE: StringGuc = StringGuc::new(None);
+pub static PROVIDER: GucSetting<ProviderKind> =
+    GucSetting::<ProviderKind>::new(ProviderKind::openai);
+pub static BASE_URL: StringGuc = StringGuc::new(None);
+pub static CHAT_COMPLETIONS_PATH: StringGuc = StringGuc::new(Some(c"/chat/completions"));
+pub static ANTHROPIC_VERSION: StringGuc = StringGuc::new(Some(c"2023-06-01"));
+pub static OLLAMA_STREAM: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static OLLAMA_NUM_CTX: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static OLLAMA_KEEP_ALIVE: StringGuc = StringGuc::new(None);
+pub static AZURE_DEPLOYMENT: StringGuc = StringGuc::new(None);
+pub static AZURE_API_VERSION: StringGuc = StringGuc::new(Some(c"2024-02-01"));
+pub static AWS_REGION: StringGuc = StringGuc::new(None);
+pub static AWS_PROFILE: StringGuc = StringGuc::new(None);
+pub static AWS_ACCESS_KEY_ID: StringGuc = StringGuc::new(None);
+pub static AWS_SECRET_ACCESS_KEY: StringGuc = StringGuc::new(None);
+pub static AWS_SESSION_TOKEN: StringGuc = StringGuc::new(None);
+pub static ON_ERROR: GucSetting<OnError> = GucSetting::<OnError>::new(OnError::error);
+pub static ON_ERROR_PLACEHOLDER: StringGuc = StringGuc::new(None);
+pub static MAX_ATTEMPTS: GucSetting<i32> = GucSetting::<i32>::new(3);
+pub static RETRY_BASE_DELAY: GucSetting<i32> = GucSetting::<i32>::new(500);
+pub static RETRY_MAX_TIME: GucSetting<i32> = GucSetting::<i32>::new(60_000);
+pub static POOL_MAX_IDLE_PER_HOST: GucSetting<i32> = GucSetting::<i32>::new(16);
+pub static POOL_IDLE_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(90_000);
+pub static CONNECT_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(10_000);
+pub static REQUEST_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(120_000);
+pub static CACHE: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static CACHE_TTL: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static CACHE_MAX_ENTRIES: GucSetting<i32> = GucSetting::<i32>::new(100_000);
+pub static SHARED_CACHE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(16);
+pub static MAX_INPUT_TOKENS: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static LONG_TEXT_STRATEGY: GucSetting<LongTextStrategy> =
+    GucSetting::<LongTextStrategy>::new(LongTextStrategy::map_reduce);
+pub static CHUNK_OVERLAP: GucSetting<i32> = GucSetting::<i32>::new(200);
+pub static BATCH_PARALLELISM: GucSetting<i32> = GucSetting::<i32>::new(8);
+/// Upper bound of `pg_summarizer.batch_parallelism` and of the `parallelism`
+/// argument that overrides it.
+pub const MAX_BATCH_PARALLELISM: i32 = 256;
+pub static WORKERS: GucSetting<i32> = GucSetting::<i32>::new(2);
+pub static DATABASE: StringGuc = StringGuc::new(Some(c"postgres"));
+pub static WORKER_NAPTIME: GucSetting<i32> = GucSetting::<i32>::new(1000);
+pub static OPENAI_BATCH_POLL_INTERVAL: GucSetting<i32> = GucSetting::<i32>::new(60);
+
+pub fn init() {
+    API_KEY.define(
+        "pg_summarizer.api_key",
+        "API key sent to the provider by summarize().",
+        "Only superusers can set it, typically with ALTER SYSTEM or in \
+        postgresql.conf, and only superusers can read it back. Not needed \
+        for ollama or bedrock.",
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+        None,
+    );
+    MODEL.define(
+        "pg_summarizer.model",
+        "Model that summarize() asks the provider to use.",
+        "When unset, a default model of the selected provider is used, \
+        e.g. gpt-3.5-turbo for openai.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    PROMPT.define(
+        "pg_summarizer.prompt",
+        "System prompt that instructs the model how to summarize.",
+        "The text to summarize is sent separately, wrapped in a <text> tag.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    DEFAULT_PROFILE.define(
+        "pg_summarizer.default_profile",
+        "Profile from summarize_profiles used when a call names none.",
+        "Columns the profile leaves NULL fall back to the other pg_summarizer \
+        settings. Unset uses the settings alone.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    GucRegistry::define_float_guc(
+        "pg_summarizer.temperature",
+        "Sampling temperature of summaries, from 0 to 2, or to 1 for anthropic and bedrock.",
+        "Lower values give more focused and repeatable summaries. -1 uses the \
+        provider default.",
+        &TEMPERATURE,
+        UNSET_TEMPERATURE,
+        *params::TEMPERATURE_RANGE.end(),
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.max_tokens",
+        "Longest summary the model may write, in tokens.",
+        "Sent as max_tokens, max_completion_tokens, maxOutputTokens or num_predict, \
+        depending on the provider. Zero uses the provider default.",
+        &MAX_TOKENS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_float_guc(
+        "pg_summarizer.top_p",
+        "Nucleus sampling probability mass of summaries, from 0 to 1.",
+        "Only tokens within this share of the probability mass are considered. \
+        -1 uses the provider default.",
+        &TOP_P,
+        UNSET_TOP_P,
+        *params::TOP_P_RANGE.end(),
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_float_guc(
+        "pg_summarizer.presence_penalty",
+        "Penalty on tokens already present in the summary, from -2 to 2.",
+        "Not sent to anthropic or bedrock, which have no such parameter. -3 uses \
+        the provider default.",
+        &PRESENCE_PENALTY,
+        UNSET_PENALTY,
+        *params::PENALTY_RANGE.end(),
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_float_guc(
+        "pg_summarizer.frequency_penalty",
+        "Penalty on tokens by how often they occur in the summary, from -2 to 2.",
+        "Not sent to anthropic or bedrock, which have no such parameter. -3 uses \
+        the provider default.",
+        &FREQUENCY_PENALTY,
+        UNSET_PENALTY,
+        *params::PENALTY_RANGE.end(),
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.seed",
+        "Seed for sampling, for summaries that repeat across calls.",
+        "Providers only make a best effort to be deterministic. Not sent to \
+        anthropic or bedrock. -1 sends no seed.",
+        &SEED,
+        UNSET_SEED,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    STOP.define(
+        "pg_summarizer.stop",
+        "Sequences at which the model stops writing the summary.",
+        "A JSON array of strings, such as [\"###\", \"END\"], or else a single \
+        sequence. The stop sequence itself is not part of the summary.",
+        GucContext::Userset,
+        GucFlags::default(),
+        Some(check_stop),
+    );
+    LENGTH.define(
+        "pg_summarizer.length",
+        "Target length of summaries.",
+        "A number followed by words, sentences or characters, such as \"50 words\". \
+        Added to the prompt as an instruction; the model may not keep to it exactly.",
+        GucContext::Userset,
+        GucFlags::default(),
+        Some(check_length),
+    );
+    FORMAT.define(
+        "pg_summarizer.format",
+        "Shape of summaries.",
+        "One of paragraph, bullets, tldr, headline or abstract, added to the prompt \
+        as an instruction. Unset leaves the shape to the prompt.",
+        GucContext::Userset,
+        GucFlags::default(),
+        Some(check_format),
+    );
+    AUDIENCE.define(
+        "pg_summarizer.audience",
+        "Readers summaries are written for.",
+        "Free text, such as \"executives\" or \"ten-year-olds\", added to the prompt.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    TONE.define(
+        "pg_summarizer.tone",
+        "Tone of summaries.",
+        "Free text, such as \"neutral\" or \"friendly\", added to the prompt.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    OUTPUT_LANGUAGE.define(
+        "pg_summarizer.output_language",
+        "Language summaries are written in.",
+        "A language name such as German, its own name such as Deutsch, an ISO 639-3 \
+        code such as deu, or source for the language of the input. Unset leaves the \
+        language to the prompt and the model.",
+        GucContext::Userset,
+        GucFlags::default(),
+        Some(check_output_language),
+    );
+    GucRegistry::define_enum_guc(
+        "pg_summarizer.language_check",
+        "What happens to a summary detected to be in another language than asked for.",
+        "off accepts it, warning emits a WARNING, retry asks once more and warns if \
+        the summary is still in another language. Summaries too short to tell their \
+        language reliably are not checked.",
+        &LANGUAGE_CHECK,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "pg_summarizer.provider",
+        "LLM API used by summarize().",
+        "One of openai, anthropic, ollama, azure, gemini or bedrock. The openai provider also covers any \
+        OpenAI-compatible server reachable through pg_summarizer.base_url. Only superusers \
+        can set it, as it decides where pg_summarizer.api_key is sent.",
+        &PROVIDER,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    BASE_URL.define(
+        "pg_summarizer.base_url",
+        "Base URL of the provider API used by summarize().",
+        "Defaults to the public endpoint of the selected provider. Point this \
+        at vLLM, llama.cpp, LiteLLM, a proxy or a local stand-in instead. \
+        For azure, set it to the resource endpoint, e.g. https://NAME.openai.azure.com. \
+        Only superusers can set it, as pg_summarizer.api_key is sent there.",
+        GucContext::Suset,
+        GucFlags::default(),
+        Some(check_base_url),
+    );
+    CHAT_COMPLETIONS_PATH.define(
+        "pg_summarizer.chat_completions_path",
+        "Path of the OpenAI chat completions endpoint, relative to pg_summarizer.base_url.",
+        "Must start with a slash. Change it only for servers that mount the \
+        chat completions API somewhere other than /chat/completions.",
+        GucContext::Userset,
+        GucFlags::default(),
+        Some(check_path),
+    );
+    ANTHROPIC_VERSION.define(
+        "pg_summarizer.anthropic_version",
+        "Value of the anthropic-version header sent to the Anthropic Messages API.",
+        "Pins the request and response format; see Anthropic's API versioning documentation.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    GucRegistry::define_bool_guc(
+        "pg_summarizer.ollama_stream",
+        "Whether to request a streamed (NDJSON) response from Ollama.",
+        "The summary is still returned in one piece once the stream ends; \
+        streaming keeps long generations from tripping idle timeouts on proxies.",
+        &OLLAMA_STREAM,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.ollama_num_ctx",
+        "Context window size, in tokens, requested from Ollama.",
+        "Zero leaves the model's own default in place.",
+        &OLLAMA_NUM_CTX,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    OLLAMA_KEEP_ALIVE.define(
+        "pg_summarizer.ollama_keep_alive",
+        "How long Ollama keeps the model loaded after a request.",
+        "A number of seconds or a duration such as 10m or 1h; a negative \
+        value keeps the model loaded indefinitely. Unset uses the server default.",
+        GucContext::Userset,
+        GucFlags::default(),
+        Some(check_keep_alive),
+    );
+    AZURE_DEPLOYMENT.define(
+        "pg_summarizer.azure_deployment",
+        "Azure OpenAI deployment that serves summarize() requests.",
+        "When unset, the model name is used as the deployment name.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    AZURE_API_VERSION.define(
+        "pg_summarizer.azure_api_version",
+        "Azure OpenAI REST API version, sent as the api-version query parameter.",
+        "Must be a version your resource supports, e.g. 2024-02-01 or a newer preview.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    AWS_REGION.define(
+        "pg_summarizer.aws_region",
+        "AWS region of the Bedrock endpoint.",
+        "When unset, AWS_REGION or AWS_DEFAULT_REGION from the server \
+        environment is used, then us-east-1. Only superusers can set it, as it \
+        decides where signed requests are sent.",
+        GucContext::Suset,
+        GucFlags::default(),
+        Some(check_aws_region),
+    );
+    AWS_PROFILE.define(
+        "pg_summarizer.aws_profile",
+        "Profile to read from the shared AWS credentials file.",
+        "Only consulted when no credentials are set explicitly or in the \
+        server environment. Defaults to AWS_PROFILE, then default.",
+        GucContext::Suset,
+        GucFlags::default(),
+        None,
+    );
+    AWS_ACCESS_KEY_ID.define(
+        "pg_summarizer.aws_access_key_id",
+        "AWS access key ID used to sign Bedrock requests.",
+        "When unset, credentials come from the server environment or the \
+        shared AWS credentials file.",
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+        None,
+    );
+    AWS_SECRET_ACCESS_KEY.define(
+        "pg_summarizer.aws_secret_access_key",
+        "AWS secret access key used to sign Bedrock requests.",
+        "Set together with pg_summarizer.aws_access_key_id.",
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+        None,
+    );
+    AWS_SESSION_TOKEN.define(
+        "pg_summarizer.aws_session_token",
+        "AWS session token, for temporary credentials.",
+        "Only needed for credentials issued by STS, e.g. through AssumeRole.",
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+        None,
+    );
+    GucRegistry::define_enum_guc(
+        "pg_summarizer.on_error",
+        "What summarize() does when a summary cannot be produced.",
+        "One of error (raise it), null (return NULL), warning (emit a WARNING \
+        and return NULL) or placeholder (return pg_summarizer.on_error_placeholder). \
+        Anything but error lets bulk statements keep the summaries that succeeded.",
+        &ON_ERROR,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    ON_ERROR_PLACEHOLDER.define(
+        "pg_summarizer.on_error_placeholder",
+        "Text returned by summarize() in place of a failed summary.",
+        "Only used when pg_summarizer.on_error is placeholder. Unset returns an empty string.",
+        GucContext::Userset,
+        GucFlags::default(),
+        None,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.max_attempts",
+        "Maximum number of attempts summarize() makes per summary.",
+        "Rate limits (HTTP 429), server errors (5xx), timeouts and connection \
+        failures are retried up to this many attempts in total; 1 disables retries.",
+        &MAX_ATTEMPTS,
+        1,
+        100,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.retry_base_delay",
+        "Delay before the first retry, doubled for each further one.",
+        "Up to half of each delay is taken off at random so that concurrent \
+        sessions spread out. A Retry-After or x-ratelimit-reset-* header sent \
+        by the provider takes precedence.",
+        &RETRY_BASE_DELAY,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.retry_max_time",
+        "Time after which summarize() stops retrying a failed call.",
+        "No retry is started that would wait past this time since the first \
+        attempt; the last error is raised instead. Zero disables the limit.",
+        &RETRY_MAX_TIME,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.pool_max_idle_per_host",
+        "Maximum number of idle connections kept open per provider host.",
+        "Each backend keeps its own pool of keep-alive connections, so that \
+        consecutive calls skip the TCP and TLS handshakes. Zero disables pooling.",
+        &POOL_MAX_IDLE_PER_HOST,
+        0,
+        1024,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.pool_idle_timeout",
+        "Time after which an idle pooled connection is closed.",
+        "Zero keeps idle connections open until the provider closes them.",
+        &POOL_IDLE_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.connect_timeout",
+        "Maximum time to wait for a connection to the provider.",
+        "Covers the TCP and TLS handshakes. Zero waits indefinitely.",
+        &CONNECT_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.request_timeout",
+        "Maximum time a single request to the provider may take.",
+        "Counted from connecting until the whole response is read; a request \
+        that times out may be retried. Zero waits indefinitely, though a query \
+        cancel or statement_timeout still interrupts the request.",
+        &REQUEST_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_MS,
+    );
+    GucRegistry::define_bool_guc(
+        "pg_summarizer.cache",
+        "Whether summarize() reuses summaries stored in summarize_cache.",
+        "Entries are keyed by a hash of the whitespace-normalized input, the \
+        provider, the model, the prompt and the generation parameters. Only \
+        successful summaries are stored.",
+        &CACHE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.cache_ttl",
+        "Time after which a cached summary is no longer used.",
+        "Expired entries are deleted as new ones are stored, or with \
+        summarize_cache_purge(true). Zero keeps entries until they are evicted.",
+        &CACHE_TTL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.cache_max_entries",
+        "Maximum number of summaries kept in summarize_cache.",
+        "The least recently used entries are evicted beyond it, checked every 100 \
+        summaries a session caches. Zero disables the limit.",
+        &CACHE_MAX_ENTRIES,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.shared_cache_size",
+        "Shared memory set aside for recently used summaries.",
+        "Only allocated when pg_summarize is listed in shared_preload_libraries. \
+        All backends consult it before summarize_cache when pg_summarizer.cache \
+        is on. Zero disables it.",
+        &SHARED_CACHE_SIZE,
+        0,
+        1024 * 1024,
+        GucContext::Postmaster,
+        GucFlags::UNIT_MB,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.max_input_tokens",
+        "Longest input sent to the provider in one request, in tokens.",
+        "Longer inputs are handled according to pg_summarizer.long_text_strategy, \
+        as are inputs that do not fit the context window of a known model. \
+        Zero leaves the context window as the only limit.",
+        &MAX_INPUT_TOKENS,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "pg_summarizer.long_text_strategy",
+        "How summarize() handles inputs too long for a single request.",
+        "One of error (raise an error), truncate (summarize the beginning only), \
+        map_reduce (summarize each chunk, then the summaries, until they fit) or \
+        refine (summarize the first chunk, then update the summary with each next one).",
+        &LONG_TEXT_STRATEGY,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.chunk_overlap",
+        "Tokens repeated from the end of one chunk at the start of the next.",
+        "Keeps sentences that straddle a chunk boundary intact in one of the \
+        chunks when a long input is split. At most half of a chunk is repeated.",
+        &CHUNK_OVERLAP,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.batch_parallelism",
+        "Maximum number of concurrent requests made by summarize_batch().",
+        "Requests beyond it wait for one in flight to finish. The parallelism \
+        argument of summarize_batch() overrides it for a single call.",
+        &BATCH_PARALLELISM,
+        1,
+        MAX_BATCH_PARALLELISM,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.workers",
+        "Number of background workers running summarize_async() jobs.",
+        "Only started when pg_summarize is listed in shared_preload_libraries. \
+        Zero disables them; jobs can still be run with summarize_run_jobs().",
+        &WORKERS,
+        0,
+        64,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    DATABASE.define(
+        "pg_summarizer.database",
+        "Database the background workers take jobs from.",
+        "The extension must be installed there. Provider settings the workers \
+        use come from the server configuration, or ALTER DATABASE ... SET.",
+        GucContext::Postmaster,
+        GucFlags::default(),
+        None,
+    );
+    GucRegistry::define_int_guc(
+        "pg_summarizer.worker_naptime",
+        "Time a background worker sleeps when there are no queued jobs.",
+        "Workers also wake up to check for jobs when the configuration is reloaded.",
+        &WORKER_NAPTIME,
+        10,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_int_guc(
+        "pg_summarizer.openai_batch_poll_interval",
+        "Time between checks on unfinished OpenAI batches.",
+        "The first background worker checks every batch in \
+        summarize_openai_batches that has not ended, and writes back the \
+        results of those that have.",
+        &OPENAI_BATCH_POLL_INTERVAL,
+        1,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
+    );
+
+    unsafe {
+        #[cfg(any(feature = "pg15", feature = "pg16"))]
+        pg_sys::MarkGUCPrefixReserved(c"pg_summarizer".as_ptr());
+        #[cfg(not(any(feature = "pg15", feature = "pg16")))]
+        pg_sys::EmitWarningsOnPlaceholders(c"pg_summarizer".as_ptr());
+    }
+}
+
+/// A string setting whose new values can be validated by a check hook, so a bad
+/// value is rejected by `SET` instead of surfacing on the next API call.
+pub struct StringGuc {
+    value: UnsafeCell<*mut c_char>,
+    boot_val: Option<&'static CStr>,
+}
+
+unsafe impl Sync for StringGuc {}
+
+impl StringGuc {
+    pub const fn new(boot_val: Option<&'static CStr>) -> Self {
+        StringGuc {
+            value: UnsafeCell::new(std::ptr::null_mut()),
+            boot_val,
+        }
+    }
+
+    /// Returns the current value, treating an empty string the same as unset.
+    pub fn get(&self) -> Option<String> {
+        unsafe {
+            let value = *self.value.get();
+            if value.is_null() {
+                return None;
+            }
+            let value = CStr::from_ptr(value).to_string_lossy();
+            (!value.is_empty()).then(|| value.into_owned())
+        }
+    }
+
+    fn define(
+        &'static self,
+        name: &str,
+        short_description: &str,
+        long_description: &str,
+        context: GucContext,
+        flags: GucFlags,
+        check_hook: pg_sys::GucStringCheckHook,
+    ) {
+        unsafe {
+            let boot_val = self.boot_val.map_or(std::ptr::null(), |s| s.as_ptr());
+            *self.value.get() = boot_val as *mut _;
+            pg_sys::DefineCustomStringVariable(
+                PgMemoryContexts::TopMemoryContext.pstrdup(name),
+                PgMemoryContexts::TopMemoryContext.pstrdup(short_description),
+                PgMemoryContexts::TopMemoryContext.pstrdup(long_description),
+                self.value.get(),
+                boot_val,
+                context as isize as u32,
+                flags.bits(),
+                check_hook,
+                None,
+                None,
+            );
+        }
+    }
+}
+
+/// Runs `validate` over the proposed value of a string setting. On failure the
+/// message is handed to Postgres as the DETAIL of the resulting error.
+unsafe fn check_string(newval: *mut *mut c_char, validate: fn(&str) -> Result<(), String>) -> bool {
+    if (*newval).is_null() {
+        return true;
+    }
+    let value = CStr::from_ptr(*newval).to_string_lossy();
+    match validate(&value) {
+        Ok(()) => true,
+        Err(detail) => {
+            pg_sys::GUC_check_errdetail_string =
+                PgMemoryContexts::CurrentMemoryContext.pstrdup(&detail);
+            false
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_base_url(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_base_url)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_path(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_path)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_keep_alive(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_keep_alive)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_aws_region(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_aws_region)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_stop(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_stop)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_length(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_length)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_format(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_format)
+}
+
+#[pg_guard]
+unsafe extern "C" fn check_output_language(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    check_string(newval, validate_output_language)
+}
+
+pub fn validate_base_url(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    let url = Url::parse(value).map_err(|e| format!("Not a valid URL: {}.", e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "Unsupported URL scheme \"{}\"; use http or https.",
+            url.scheme()
+        ));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err("The base URL must not contain a query string or fragment.".into());
+    }
+    Ok(())
+}
+
+fn validate_path(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    if !value.starts_with('/') {
+        return Err("The path must start with \"/\".".into());
+    }
+    // "//host/path" would lead away from pg_summarizer.base_url.
+    if value.starts_with("//") {
+        return Err("The path must not start with \"//\".".into());
+    }
+    Url::parse("http://localhost")
+        .and_then(|base| base.join(value))
+        .map(|_| ())
+        .map_err(|e| format!("Not a valid URL path: {}.", e))
+}
+
+/// Accepts region names such as `eu-central-1`, which end up in the host name
+/// of the Bedrock endpoint.
+pub fn validate_aws_region(value: &str) -> Result<(), String> {
+    if value
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    {
+        return Ok(());
+    }
+    Err("Use lowercase letters, digits and hyphens only, as in us-east-1.".into())
+}
+
+fn validate_stop(value: &str) -> Result<(), String> {
+    params::parse_stop(value).map(|_| ())
+}
+
+fn validate_length(value: &str) -> Result<(), String> {
+    if value.is_empty() || style::Length::parse(value).is_ok() {
+        return Ok(());
+    }
+    Err("Use a number followed by words, sentences or characters, such as \"50 words\".".into())
+}
+
+fn validate_format(value: &str) -> Result<(), String> {
+    if value.is_empty() || style::Format::parse(value).is_ok() {
+        return Ok(());
+    }
+    Err("Use paragraph, bullets, tldr, headline or abstract.".into())
+}
+
+fn validate_output_language(value: &str) -> Result<(), String> {
+    if value.is_empty() || OutputLanguage::parse(value).is_ok() {
+        return Ok(());
+    }
+    Err("Use source, a language name such as German, or an ISO 639-3 code such as deu.".into())
+}
+
+/// Accepts what Ollama accepts for `keep_alive`: whole seconds, or a Go-style
+/// duration made of number and unit pairs such as `1h30m`.
+fn validate_keep_alive(value: &str) -> Result<(), String> {
+    if value.is_empty() || value.parse::<i64>().is_ok() {
+        return Ok(());
+    }
+    let invalid = || {
+        format!(
+            "\"{}\" is neither a number of seconds nor a duration.",
+            value
+        )
+    };
+    let mut rest = value.strip_prefix('-').unwrap_or(value);
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(invalid)?;
+        if digits == 0 {
+            return Err(invalid());
+        }
+        rest = &rest[digits..];
+        let unit = ["ns", "us", "µs", "ms", "s", "m", "h"]
+            .into_iter()
+            .filter(|unit| rest.starts_with(unit))
+            .max_by_key(|unit| unit.len())
+            .ok_or_else(invalid)?;
+        rest = &rest[unit.len()..];
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! The HTTP client shared by every `summarize()` call in a backend.
+//!
+//! Building a client per call means a new connection, and TLS handshake, per
+//! row. Instead one client is kept for the life of the backend, with a pool of
+//! keep-alive connections, and replaced only when a setting it was built from
+//! changes.
+//!
+//! Requests run on a single-threaded runtime owned by the backend, which stops
+//! every few milliseconds to process interrupts. A query cancel or
+//! `statement_timeout` therefore raises its error promptly, dropping the
+//! request and its connection, instead of waiting for the provider.
+
+use crate::guc;
+use pgrx::check_for_interrupts;
+use reqwest::Client;
+use std::cell::RefCell;
+use std::future::Future;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// How often an in-flight request is paused to check for interrupts.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The settings a client is built from.
+#[derive(Clone, PartialEq)]
+struct ClientSettings {
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+impl ClientSettings {
+    fn current() -> Self {
+        ClientSettings {
+            pool_max_idle_per_host: guc::POOL_MAX_IDLE_PER_HOST.get() as usize,
+            pool_idle_timeout: millis(guc::POOL_IDLE_TIMEOUT.get()),
+            connect_timeout: millis(guc::CONNECT_TIMEOUT.get()),
+            request_timeout: millis(guc::REQUEST_TIMEOUT.get()),
+        }
+    }
+
+    fn build(&self) -> reqwest::Result<Client> {
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(Duration::from_secs(60));
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.request_timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder.build()
+    }
+}
+
+/// A setting in milliseconds, where zero means no limit.
+fn millis(value: i32) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_millis(value as u64))
+}
+
+thread_local! {
+    static CLIENT: RefCell<Option<(ClientSettings, Client)>> = const { RefCell::new(None) };
+    static RUNTIME: Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not start the HTTP runtime");
+}
+
+/// Returns the backend's client, building it on first use or after a
+/// relevant setting changed.
+pub fn client() -> reqwest::Result<Client> {
+    let settings = ClientSettings::current();
+    CLIENT.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        match &*cached {
+            Some((built_with, client)) if *built_with == settings => Ok(client.clone()),
+            _ => {
+                // Pooled connections belong to the runtime, so build there.
+                let client = RUNTIME.with(|runtime| {
+                    let _guard = runtime.enter();
+                    settings.build()
+                })?;
+                *cached = Some((settings, client.clone()));
+                Ok(client)
+            }
+        }
+    })
+}
+
+/// Runs `future` to completion on the backend's runtime, processing
+/// interrupts while it is pending. A cancel raises its error from here.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    loop {
+        let polled = RUNTIME.with(|runtime| {
+            runtime.block_on(async {
+                tokio::time::timeout(INTERRUPT_POLL_INTERVAL, &mut future).await
+            })
+        });
+        match polled {
+            Ok(output) => return output,
+            Err(_) => {
+                check_for_interrupts!();
+            }
+        }
+    }
+}
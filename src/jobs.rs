@@ -0,0 +1,221 @@
+//! Summarization jobs queued in `summarize_jobs` and run outside the caller's
+//! transaction, normally by the background workers in [`crate::worker`].
+//!
+//! A job is claimed with `FOR UPDATE SKIP LOCKED` and finished in the same
+//! transaction, so concurrent workers never run the same job, and a job whose
+//! worker dies goes back to the queue when its transaction aborts.
+
+use crate::registrations;
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+-- The settings a job is run with besides its model and prompt. Only these are
+-- applied, so a job cannot change where requests go or which key they carry.
+CREATE FUNCTION summarize_job_setting_names() RETURNS text[] LANGUAGE sql IMMUTABLE AS $$
+    SELECT ARRAY[
+        'pg_summarizer.default_profile', 'pg_summarizer.temperature', 'pg_summarizer.max_tokens',
+        'pg_summarizer.top_p', 'pg_summarizer.presence_penalty',
+        'pg_summarizer.frequency_penalty', 'pg_summarizer.seed', 'pg_summarizer.stop',
+        'pg_summarizer.length', 'pg_summarizer.format', 'pg_summarizer.audience',
+        'pg_summarizer.tone', 'pg_summarizer.output_language', 'pg_summarizer.language_check',
+        'pg_summarizer.max_input_tokens', 'pg_summarizer.long_text_strategy',
+        'pg_summarizer.chunk_overlap'
+    ]
+$$;
+
+-- Those settings in the session that queues a job, so the summary is the one
+-- summarize() would make.
+CREATE FUNCTION summarize_job_settings() RETURNS jsonb LANGUAGE sql STABLE AS $$
+    SELECT coalesce(jsonb_object_agg(name, setting) FILTER (WHERE setting IS NOT NULL), '{}')
+    FROM unnest(summarize_job_setting_names()) AS name, current_setting(name, true) AS setting
+$$;
+
+CREATE TABLE summarize_jobs (
+    id bigserial PRIMARY KEY,
+    input text NOT NULL,
+    model text,
+    prompt text,
+    settings jsonb NOT NULL DEFAULT summarize_job_settings(),
+    status text NOT NULL DEFAULT 'queued' CHECK (status IN ('queued', 'done', 'failed')),
+    summary text,
+    error text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    finished_at timestamptz,
+    -- Where summarize_register() jobs write the summary back to.
+    target_relation regclass,
+    source_column text,
+    target_column text,
+    target_key jsonb
+);
+CREATE INDEX summarize_jobs_queued ON summarize_jobs (id) WHERE status = 'queued';
+COMMENT ON TABLE summarize_jobs IS 'Summaries requested with summarize_async()';
+"#,
+    name = "summarize_jobs",
+);
+
+/// Queues `input` for summarization and returns the job id. The job is run
+/// with the model, prompt, profile, generation parameters and style of the
+/// session, not of the worker. A NULL `input` queues nothing and gives NULL.
+#[pg_extern]
+fn summarize_async(
+    input: Option<&str>,
+    model: default!(Option<&str>, "NULL"),
+    prompt: default!(Option<&str>, "NULL"),
+) -> Option<i64> {
+    let input = input?;
+    let model = model
+        .map(str::to_string)
+        .or_else(|| crate::guc::MODEL.get());
+    let prompt = prompt
+        .map(str::to_string)
+        .or_else(|| crate::guc::PROMPT.get());
+    Spi::get_one_with_args::<i64>(
+        "INSERT INTO summarize_jobs (input, model, prompt) VALUES ($1, $2, $3) RETURNING id",
+        vec![
+            (PgBuiltInOids::TEXTOID.oid(), input.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), model.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), prompt.into_datum()),
+        ],
+    )
+    .unwrap_or_else(|e| error!("could not queue summarization job: {}", e))
+}
+
+/// Returns the summary of a finished job, or NULL while it is queued. Raises
+/// an error if the job failed or does not exist.
+#[pg_extern]
+fn summarize_result(job_id: i64) -> Option<String> {
+    let (status, summary, error) = Spi::get_three_with_args::<String, String, String>(
+        "SELECT job.status, job.summary, job.error \
+        FROM (SELECT) AS one LEFT JOIN summarize_jobs AS job ON job.id = $1",
+        vec![(PgBuiltInOids::INT8OID.oid(), job_id.into_datum())],
+    )
+    .unwrap_or_else(|e| error!("could not read summarization job: {}", e));
+    match status.as_deref() {
+        Some("failed") => {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION,
+                format!("summarization job {} failed", job_id),
+                error.unwrap_or_default()
+            );
+        }
+        None => {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_NO_DATA_FOUND,
+                format!("summarization job {} does not exist", job_id)
+            );
+        }
+        _ => {}
+    }
+    // Only set once the job is done.
+    summary
+}
+
+/// Runs up to `max_jobs` queued jobs, or all of them, in the current
+/// transaction and returns how many were run. Useful where no background
+/// workers are configured.
+#[pg_extern]
+fn summarize_run_jobs(max_jobs: default!(Option<i32>, "NULL")) -> i32 {
+    let mut ran = 0;
+    while max_jobs.is_none_or(|max_jobs| ran < max_jobs) && run_next_job() {
+        ran += 1;
+    }
+    ran
+}
+
+/// Whether `summarize_jobs` exists, i.e. the extension is installed in the
+/// current database.
+pub fn queue_exists() -> bool {
+    Spi::get_one::<bool>("SELECT to_regclass('summarize_jobs') IS NOT NULL")
+        .unwrap_or_default()
+        .unwrap_or_default()
+}
+
+/// Claims the oldest queued job, summarizes it and records the outcome, after
+/// writing the summary to its row for a registered column. Returns false if
+/// there was no job to run.
+pub fn run_next_job() -> bool {
+    let job = Spi::connect(|mut client| {
+        let table = client.update(
+            "SELECT id, input, model, prompt, target_relation IS NOT NULL, settings \
+            FROM summarize_jobs WHERE status = 'queued' \
+            ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED",
+            Some(1),
+            None,
+        )?;
+        if table.is_empty() {
+            return Ok(None);
+        }
+        let row = table.first();
+        Ok::<_, pgrx::spi::Error>(Some((
+            row.get::<i64>(1)?.unwrap_or_default(),
+            row.get::<String>(2)?.unwrap_or_default(),
+            row.get::<String>(3)?,
+            row.get::<String>(4)?,
+            row.get::<bool>(5)?.unwrap_or_default(),
+            row.get::<pgrx::JsonB>(6)?,
+        )))
+    })
+    .unwrap_or_else(|e| error!("could not claim summarization job: {}", e));
+    let Some((id, input, model, prompt, registered, settings)) = job else {
+        return false;
+    };
+
+    let previous = settings.map(|settings| set_local(&settings));
+    let result = crate::try_summarize(
+        &input,
+        None,
+        model.as_deref(),
+        prompt.as_deref(),
+        Default::default(),
+        Default::default(),
+    );
+    if let Some(previous) = previous {
+        set_local(&previous);
+    }
+    let (status, summary, error) = match result {
+        Ok(summary) => match registered.then(|| registrations::write_back(id, &summary)) {
+            Some(Some(error)) => ("failed", Some(summary), Some(error)),
+            _ => ("done", Some(summary), None),
+        },
+        Err(e) => ("failed", None, Some(e.to_string())),
+    };
+    Spi::run_with_args(
+        "UPDATE summarize_jobs SET status = $2, summary = $3, error = $4, finished_at = now() \
+        WHERE id = $1",
+        Some(vec![
+            (PgBuiltInOids::INT8OID.oid(), id.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), status.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), summary.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), error.into_datum()),
+        ]),
+    )
+    .unwrap_or_else(|e| error!("could not record summarization job {}: {}", id, e));
+    true
+}
+
+/// Sets each setting of the JSON object `settings` that jobs may set for the
+/// rest of the transaction, and returns the values they had before.
+fn set_local(settings: &pgrx::JsonB) -> pgrx::JsonB {
+    let settings = vec![(
+        PgBuiltInOids::JSONBOID.oid(),
+        pgrx::JsonB(settings.0.clone()).into_datum(),
+    )];
+    Spi::get_one_with_args::<pgrx::JsonB>(
+        "SELECT jsonb_object_agg(key, coalesce(current_setting(key, true), '')) \
+        FROM jsonb_each_text($1) WHERE key = ANY (summarize_job_setting_names())",
+        settings.clone(),
+    )
+    .and_then(|previous| {
+        Spi::run_with_args(
+            "SELECT set_config(key, value, true) FROM jsonb_each_text($1) \
+            WHERE key = ANY (summarize_job_setting_names())",
+            Some(settings),
+        )?;
+        Ok(previous)
+    })
+    .unwrap_or_else(|e| error!("could not apply the settings of a summarization job: {}", e))
+    .unwrap_or(pgrx::JsonB(serde_json::json!({})))
+}
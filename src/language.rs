@@ -0,0 +1,93 @@
+//! The language summaries are written in.
+//!
+//! `pg_summarizer.output_language` (or the `language` argument) adds an
+//! instruction to the prompt, and the answer is then checked with local
+//! language detection: one found to be in another language is asked for again
+//! or warned about, as `pg_summarizer.language_check` says. Texts too short to
+//! tell their language reliably are not checked.
+
+use pgrx::PostgresGucEnum;
+use whatlang::Lang;
+
+/// Values accepted by `pg_summarizer.language_check`.
+#[allow(non_camel_case_types)]
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Debug)]
+pub enum LanguageCheck {
+    /// Accept summaries in any language.
+    off,
+    /// Emit a WARNING for a summary in another language.
+    warning,
+    /// Ask once more, then warn if the summary is still in another language.
+    retry,
+}
+
+/// Characters of a text its language is detected from; more only takes longer.
+const DETECT_CHARS: usize = 2000;
+
+/// The language asked of a summary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputLanguage {
+    /// The language of the input.
+    Source,
+    Lang(Lang),
+}
+
+impl OutputLanguage {
+    /// Parses `source`, or a language by its English name, its own name or
+    /// its ISO 639-3 code, such as `German`, `Deutsch` or `deu`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("source") {
+            return Ok(OutputLanguage::Source);
+        }
+        Lang::from_code(value.to_lowercase())
+            .or_else(|| {
+                Lang::all().iter().copied().find(|lang| {
+                    lang.eng_name().eq_ignore_ascii_case(value)
+                        || lang.name().to_lowercase() == value.to_lowercase()
+                })
+            })
+            .map(OutputLanguage::Lang)
+            .ok_or_else(|| {
+                format!(
+                    "language must be source, a language name such as German or an ISO 639-3 \
+                    code such as deu, not \"{}\"",
+                    value
+                )
+            })
+    }
+
+    pub fn instruction(self) -> String {
+        match self {
+            OutputLanguage::Source => {
+                "Write the summary in the language of the <text>.".to_string()
+            }
+            OutputLanguage::Lang(lang) => {
+                format!("Write the summary in {}.", lang.eng_name())
+            }
+        }
+    }
+
+    /// The language `summary` should be in and the one it is in, if it is
+    /// reliably another. `input` is what was summarized.
+    pub fn mismatch(self, input: &str, summary: &str) -> Option<(Lang, Lang)> {
+        let expected = match self {
+            OutputLanguage::Source => detect(input)?,
+            OutputLanguage::Lang(lang) => lang,
+        };
+        detect(summary)
+            .filter(|&found| found != expected)
+            .map(|found| (expected, found))
+    }
+}
+
+/// The language of `text`, unless it cannot be told reliably.
+fn detect(text: &str) -> Option<Lang> {
+    let end = text
+        .char_indices()
+        .nth(DETECT_CHARS)
+        .map_or(text.len(), |(i, _)| i);
+    whatlang::detect(&text[..end])
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang())
+}
@@ -1,88 +1,536 @@
+use error::{ErrorKind, OnError, SummarizeError};
+use futures_util::{stream, StreamExt};
+use language::LanguageCheck;
+use long_text::LongTextStrategy;
+use params::GenerationParams;
 use pgrx::prelude::*;
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
-use serde_json::json;
+use pgrx::PgSharedMemoryInitialization;
+use profiles::Profile;
+use providers::{Provider, ProviderKind, SummaryRequest};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use reqwest::Client;
+use reqwest::StatusCode;
+use retry::RetryPolicy;
+use std::time::SystemTime;
+use style::SummaryStyle;
+use tokens::Tokenizer;
+
+mod batch;
+mod cache;
+mod error;
+mod guc;
+mod http;
+mod jobs;
+mod language;
+mod long_text;
+mod openai_batch;
+mod params;
+mod profiles;
+mod providers;
+mod registrations;
+mod retry;
+mod shared_cache;
+mod style;
+mod tokens;
+mod worker;
 
 pgrx::pg_module_magic!();
 
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+    guc::init();
+    if shared_cache::SharedCache::wanted() {
+        pgrx::pg_shmem_init!(shared_cache::SHARED_CACHE);
+    }
+    worker::register();
+}
+
 #[pg_extern]
 fn hello_pg_summarize() -> &'static str {
     "Hello, pg_summarize"
 }
 
+/// Summarizes `input`. The model, prompt, style and generation parameters
+/// override those of `profile` and the `pg_summarizer.*` settings for this
+/// call when given. A NULL `input` gives NULL, as from a strict function.
+#[allow(clippy::too_many_arguments)]
 #[pg_extern]
-fn summarize(input: &str) -> String {
-    let api_key = Spi::get_one::<&str>("SELECT current_setting('pg_summarizer.api_key', true)")
-        .expect("failed to get 'pg_summarizer.api_key' setting")
-        .expect("got null for 'pg_summarizer.api_key' setting");
-
-    let model = match Spi::get_one::<&str>("SELECT current_setting('pg_summarizer.model', true)") {
-        Ok(Some(model_name)) => model_name,
-        _ => "gpt-3.5-turbo",
+fn summarize(
+    input: Option<&str>,
+    model: default!(Option<&str>, "NULL"),
+    prompt: default!(Option<&str>, "NULL"),
+    max_tokens: default!(Option<i32>, "NULL"),
+    temperature: default!(Option<f64>, "NULL"),
+    top_p: default!(Option<f64>, "NULL"),
+    presence_penalty: default!(Option<f64>, "NULL"),
+    frequency_penalty: default!(Option<f64>, "NULL"),
+    seed: default!(Option<i64>, "NULL"),
+    stop: default!(Option<Vec<String>>, "NULL"),
+    length: default!(Option<&str>, "NULL"),
+    format: default!(Option<&str>, "NULL"),
+    audience: default!(Option<&str>, "NULL"),
+    tone: default!(Option<&str>, "NULL"),
+    language: default!(Option<&str>, "NULL"),
+    on_error: default!(Option<&str>, "NULL"),
+    profile: default!(Option<&str>, "NULL"),
+) -> Option<String> {
+    let input = input?;
+    let on_error = on_error_mode(on_error);
+    let params = GenerationParams {
+        temperature,
+        max_tokens,
+        top_p,
+        presence_penalty,
+        frequency_penalty,
+        seed,
+        stop,
     };
-
-    let prompt = match Spi::get_one::<&str>("SELECT current_setting('pg_summarizer.prompt', true)")
+    let style = match params
+        .validate()
+        .and_then(|()| SummaryStyle::parse(length, format, audience, tone, language))
     {
-        Ok(Some(prompt_str)) => prompt_str,
-        _ => {
-            "You are an AI summarizing tool. \
-        Your purpose is to summarize the <text> tag, \
-        not to engage in conversation or discussion. \
-        Please read the <text> carefully. \
-        Then, summarize the key points. \
-        Focus on capturing the most important information as concisely as possible."
+        Ok(style) => style,
+        Err(message) => {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                message
+            );
         }
     };
+    match try_summarize(input, profile, model, prompt, style, params) {
+        Ok(summary) => Some(summary),
+        Err(e) => recover(e, on_error),
+    }
+}
 
-    match make_api_call(input, &api_key, model, prompt) {
-        Ok(summary) => summary,
-        Err(e) => panic!("Error: {}", e),
+/// The `on_error` argument, or `pg_summarizer.on_error` when it is NULL.
+fn on_error_mode(on_error: Option<&str>) -> OnError {
+    match on_error {
+        Some(mode) => OnError::parse(mode).unwrap_or_else(|e| e.report()),
+        None => guc::ON_ERROR.get(),
+    }
+}
+
+/// What a failed summary turns into under `on_error`, unless it is raised.
+fn recover(e: SummarizeError, on_error: OnError) -> Option<String> {
+    match on_error {
+        OnError::error => e.report(),
+        OnError::null => None,
+        OnError::warning => {
+            e.warn();
+            None
+        }
+        OnError::placeholder => Some(guc::ON_ERROR_PLACEHOLDER.get().unwrap_or_default()),
     }
 }
 
-fn make_api_call(
+/// Summarizes `input` with `profile`, or the default profile if NULL, using
+/// `model`, `prompt` and any of `style` and `params` instead of its settings
+/// when given.
+fn try_summarize(
     input: &str,
-    api_key: &str,
-    model: &str,
-    prompt: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let request_body = json!({
-        "model": model,
-        "messages": [
-            {
-                "role": "system",
-                "content": prompt
-            },
+    profile: Option<&str>,
+    model: Option<&str>,
+    prompt: Option<&str>,
+    style: SummaryStyle,
+    params: GenerationParams,
+) -> Result<String, SummarizeError> {
+    Summarizer::new(&Profile::configured(profile)?, model, prompt)?
+        .with_style(style)
+        .with_params(params)?
+        .summarize_all(&[input], 1)
+        .pop()
+        .expect("one result per input")
+}
+
+/// The provider, model, prompt, style and parameters summaries are
+/// requested with.
+struct Summarizer {
+    provider: Box<dyn Provider>,
+    provider_kind: ProviderKind,
+    model: String,
+    prompt: String,
+    /// Applied to the prompt of requests whose answer is the summary, not
+    /// to those for the parts of a long input.
+    style: SummaryStyle,
+    params: GenerationParams,
+    tokenizer: Tokenizer,
+}
+
+impl Summarizer {
+    /// The provider of the default profile, or of the settings, using
+    /// `model` and `prompt` instead of theirs when given.
+    fn configured(model: Option<&str>, prompt: Option<&str>) -> Result<Self, SummarizeError> {
+        Summarizer::new(&Profile::configured(None)?, model, prompt)
+    }
+
+    /// The provider of `profile`, using `model` and `prompt` instead of its
+    /// own when given.
+    fn new(
+        profile: &Profile,
+        model: Option<&str>,
+        prompt: Option<&str>,
+    ) -> Result<Self, SummarizeError> {
+        let api_key = profile.api_key.as_deref();
+
+        let provider = providers::build(
+            profile.provider,
+            profile.base_url.clone(),
+            api_key.unwrap_or_default(),
+        )?;
+        if provider.requires_api_key() && api_key.is_none() {
+            return Err(SummarizeError::new(
+                ErrorKind::MissingCredentials,
+                format!("{} is not set", profile.api_key_setting),
+            ));
+        }
+
+        let model = model
+            .map(str::to_string)
+            .or_else(|| profile.model.clone())
+            .unwrap_or_else(|| provider.default_model().to_string());
+
+        let prompt = prompt
+            .map(str::to_string)
+            .or_else(|| profile.prompt.clone())
+            .unwrap_or_else(|| guc::DEFAULT_PROMPT.to_string_lossy().into_owned());
+
+        let summarizer = Summarizer {
+            tokenizer: Tokenizer::for_model(&model),
+            provider,
+            provider_kind: profile.provider,
+            model,
+            prompt,
+            style: SummaryStyle::configured(),
+            params: profile.params.clone(),
+        };
+        summarizer.check_params()?;
+        Ok(summarizer)
+    }
+
+    /// Uses those of `style` that are given instead of their settings.
+    fn with_style(mut self, style: SummaryStyle) -> Self {
+        self.style = style.or(self.style);
+        self
+    }
+
+    /// Uses those of `params` that are given instead of their settings.
+    fn with_params(mut self, params: GenerationParams) -> Result<Self, SummarizeError> {
+        self.params = params.or(self.params);
+        self.check_params()?;
+        Ok(self)
+    }
+
+    /// Rejects parameters in the general range that the provider does not
+    /// accept, before a request is sent.
+    fn check_params(&self) -> Result<(), SummarizeError> {
+        let range = self.provider.temperature_range();
+        match self.params.temperature {
+            Some(temperature) if !range.contains(&temperature) => Err(SummarizeError::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "temperature must be between {} and {} for {}, not {}",
+                    range.start(),
+                    range.end(),
+                    self.provider_kind.name(),
+                    temperature
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn request<'a>(&'a self, input: &'a str, prompt: &'a str) -> SummaryRequest<'a> {
+        SummaryRequest {
+            input,
+            model: &self.model,
+            prompt,
+            params: &self.params,
+        }
+    }
+
+    /// Summarizes each of `inputs`, keeping up to `parallelism` requests to
+    /// the provider in flight. Inputs longer than the model's context window
+    /// or `pg_summarizer.max_input_tokens` allow are handled according to
+    /// `pg_summarizer.long_text_strategy`. The results are in the order of
+    /// `inputs`.
+    fn summarize_all(
+        &self,
+        inputs: &[&str],
+        parallelism: usize,
+    ) -> Vec<Result<String, SummarizeError>> {
+        let limit = self.input_limit();
+        let mut results: Vec<_> = inputs.iter().map(|_| None).collect();
+        let mut whole = Vec::new();
+        for (i, input) in inputs.iter().enumerate() {
+            let Some(max_tokens) = limit else {
+                whole.push((i, *input));
+                continue;
+            };
+            let tokens = self.tokenizer.count(input);
+            if tokens <= max_tokens {
+                whole.push((i, *input));
+                continue;
+            }
+            match guc::LONG_TEXT_STRATEGY.get() {
+                LongTextStrategy::error => {
+                    results[i] = Some(Err(self.too_long(tokens, max_tokens)));
+                }
+                // Too little room for parts of the input to say much.
+                _ if !self.can_split() => {
+                    results[i] = Some(Err(self.too_long(tokens, max_tokens).with_hint(
+                        "Shorten the prompt, lower pg_summarizer.max_tokens or pick a model \
+                        with a larger context window.",
+                    )));
+                }
+                LongTextStrategy::truncate => {
+                    whole.push((i, self.tokenizer.truncate(input, max_tokens)));
+                }
+                LongTextStrategy::map_reduce => {
+                    results[i] = Some(self.map_reduce(input, max_tokens, parallelism));
+                }
+                LongTextStrategy::refine => {
+                    results[i] = Some(self.refine(input, max_tokens));
+                }
+            }
+        }
+
+        let texts: Vec<&str> = whole.iter().map(|(_, text)| *text).collect();
+        let prompt = self.style.apply(&self.prompt);
+        for ((i, _), result) in
+            whole
+                .iter()
+                .zip(self.request_summaries(&prompt, &texts, parallelism))
+        {
+            results[*i] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("one result per input"))
+            .collect()
+    }
+
+    /// Sends a request for each of `inputs` with `prompt`, answering from the
+    /// cache where possible and keeping up to `parallelism` requests in
+    /// flight. The results are in the order of `inputs`.
+    fn request_all(
+        &self,
+        prompt: &str,
+        inputs: &[&str],
+        parallelism: usize,
+    ) -> Vec<Result<String, SummarizeError>> {
+        self.request_all_caching(prompt, inputs, parallelism, |_, _| true)
+    }
+
+    /// Like [`Summarizer::request_all`], caching only the answers for which
+    /// `cacheable(input, answer)` holds.
+    fn request_all_caching(
+        &self,
+        prompt: &str,
+        inputs: &[&str],
+        parallelism: usize,
+        cacheable: impl Fn(&str, &str) -> bool,
+    ) -> Vec<Result<String, SummarizeError>> {
+        let keys: Vec<_> = inputs
+            .iter()
+            .map(|input| self.cache_key(input, prompt))
+            .collect();
+        let cached = cache::lookup_all(&keys);
+
+        let missing: Vec<_> = inputs
+            .iter()
+            .zip(&cached)
+            .filter(|(_, summary)| summary.is_none())
+            .map(|(input, _)| *input)
+            .collect();
+        let mut fetched = self.fetch_all(prompt, &missing, parallelism).into_iter();
+        cached
+            .into_iter()
+            .zip(keys)
+            .zip(inputs)
+            .map(|((cached, key), input)| match cached {
+                Some(summary) => Ok(summary),
+                None => {
+                    let result = fetched.next().expect("one result per request");
+                    if let (Ok(summary), Some(key)) = (&result, key) {
+                        if cacheable(input, summary) {
+                            self.store(&key, summary);
+                        }
+                    }
+                    result
+                }
+            })
+            .collect()
+    }
+
+    /// Like [`Summarizer::request_all`], for requests whose answer is the
+    /// summary itself. Answers in another language than the style asks for
+    /// are handled according to `pg_summarizer.language_check`, and never
+    /// cached.
+    fn request_summaries(
+        &self,
+        prompt: &str,
+        inputs: &[&str],
+        parallelism: usize,
+    ) -> Vec<Result<String, SummarizeError>> {
+        let check = guc::LANGUAGE_CHECK.get();
+        let Some(language) = self.style.language.filter(|_| check != LanguageCheck::off) else {
+            return self.request_all(prompt, inputs, parallelism);
+        };
+        let mut results =
+            self.request_all_caching(prompt, inputs, parallelism, |input, summary| {
+                language.mismatch(input, summary).is_none()
+            });
+        // Indices of the summaries in another language, with the language
+        // asked for and the one found.
+        let mismatches = |results: &[Result<String, SummarizeError>]| -> Vec<_> {
+            inputs
+                .iter()
+                .zip(results)
+                .enumerate()
+                .filter_map(|(i, (input, result))| {
+                    Some((i, language.mismatch(input, result.as_ref().ok()?)?))
+                })
+                .collect()
+        };
+
+        let mut wrong = mismatches(&results);
+        if check == LanguageCheck::retry && !wrong.is_empty() {
+            let retry_inputs: Vec<_> = wrong.iter().map(|&(i, _)| inputs[i]).collect();
+            for (&(i, _), result) in
+                wrong
+                    .iter()
+                    .zip(self.fetch_all(prompt, &retry_inputs, parallelism))
             {
-                "role": "user",
-                "content": format!("<text>{}</text>", input)
+                if let (Ok(summary), Some(key)) = (&result, self.cache_key(inputs[i], prompt)) {
+                    if language.mismatch(inputs[i], summary).is_none() {
+                        self.store(&key, summary);
+                    }
+                }
+                results[i] = result;
             }
-        ]
-    });
+            wrong = mismatches(&results);
+        }
+        for (_, (expected, found)) in wrong {
+            pgrx::warning!(
+                "summary is in {} instead of {}",
+                found.eng_name(),
+                expected.eng_name()
+            );
+        }
+        results
+    }
 
-    let client = Client::new();
-    let mut headers = HeaderMap::new();
+    /// Sends a request for each of `inputs` with `prompt`, keeping up to
+    /// `parallelism` in flight. The results are in the order of `inputs`.
+    fn fetch_all(
+        &self,
+        prompt: &str,
+        inputs: &[&str],
+        parallelism: usize,
+    ) -> Vec<Result<String, SummarizeError>> {
+        match http::client() {
+            Ok(client) => {
+                let policy = RetryPolicy::configured();
+                http::block_on(
+                    stream::iter(inputs)
+                        .map(|input| {
+                            make_api_call(
+                                &client,
+                                &policy,
+                                self.provider.as_ref(),
+                                self.request(input, prompt),
+                            )
+                        })
+                        .buffered(parallelism.max(1))
+                        .collect(),
+                )
+            }
+            Err(e) => {
+                let e = SummarizeError::from(e);
+                inputs.iter().map(|_| Err(e.clone())).collect()
+            }
+        }
+    }
+
+    fn cache_key(&self, input: &str, prompt: &str) -> Option<[u8; 32]> {
+        cache::enabled().then(|| {
+            let request = self.request(input, prompt);
+            cache::key(self.provider.as_ref(), self.provider_kind.name(), &request)
+        })
+    }
+
+    fn store(&self, key: &[u8; 32], summary: &str) {
+        cache::store(key, self.provider_kind.name(), &self.model, summary);
+    }
+}
+
+async fn make_api_call(
+    client: &Client,
+    policy: &RetryPolicy,
+    provider: &dyn Provider,
+    request: SummaryRequest<'_>,
+) -> Result<String, SummarizeError> {
+    let url = provider.endpoint(&request);
+    let request_body = serde_json::to_vec(&provider.request_body(&request))?;
+
+    policy
+        .run(|| send_request(client, provider, &url, &request_body))
+        .await
+}
+
+/// Makes a single attempt at the API call; transient failures are marked
+/// retryable for the caller.
+async fn send_request(
+    client: &Client,
+    provider: &dyn Provider,
+    url: &str,
+    request_body: &[u8],
+) -> Result<String, SummarizeError> {
+    // Headers are rebuilt for every attempt so that signatures stay fresh.
+    let mut headers = provider.auth_headers()?;
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", api_key))?,
-    );
+    provider.sign(url, &mut headers, request_body)?;
 
     let response = client
-        .post("https://api.openai.com/v1/chat/completions")
+        .post(url)
         .headers(headers)
-        .json(&request_body)
-        .send()?;
+        .body(request_body.to_vec())
+        .send()
+        .await?;
+
+    let body = response_body(response).await?;
+    provider
+        .parse_response(&body)
+        .map_err(|e| e.with_detail(body))
+}
 
-    if response.status().is_success() {
-        let response_json: serde_json::Value = response.json()?;
-        if let Some(summary) = response_json["choices"][0]["message"]["content"].as_str() {
-            Ok(summary.to_string())
+/// Reads the body of a successful response; an error status becomes an
+/// error, marked retryable if it is transient.
+async fn response_body(response: reqwest::Response) -> Result<String, SummarizeError> {
+    let status = response.status();
+    let retry_after = retry::retry_after(response.headers(), SystemTime::now());
+    let body = response.text().await?;
+    if status.is_success() {
+        Ok(body)
+    } else if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(
+            SummarizeError::new(ErrorKind::RateLimited, "provider rate limit exceeded")
+                .with_detail(body)
+                .retryable(retry_after),
+        )
+    } else {
+        let error = SummarizeError::new(
+            ErrorKind::Status,
+            format!("provider request failed with status {}", status),
+        )
+        .with_detail(body);
+        if status.is_server_error() {
+            Err(error.retryable(retry_after))
         } else {
-            Err("Unexpected response format".into())
+            Err(error)
         }
-    } else {
-        Err(format!("Request failed with status: {}", response.status()).into())
     }
 }
 
@@ -95,6 +543,1866 @@ mod tests {
     fn test_hello_pg_summarize() {
         assert_eq!("Hello, pg_summarize", crate::hello_pg_summarize());
     }
+
+    #[pg_test]
+    fn test_chat_completions_url() {
+        Spi::run("SET pg_summarizer.base_url = 'http://localhost:8000/v1/'").unwrap();
+        let provider = crate::providers::configured("sk-test").unwrap();
+        assert_eq!(
+            "http://localhost:8000/v1/chat/completions",
+            provider.endpoint(&summary_request("text"))
+        );
+    }
+
+    #[pg_test(
+        error = "invalid value for parameter \"pg_summarizer.base_url\": \"ftp://example.com\""
+    )]
+    fn test_base_url_rejects_unsupported_scheme() {
+        Spi::run("SET pg_summarizer.base_url = 'ftp://example.com'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_anthropic_request() {
+        Spi::run("SET pg_summarizer.provider = 'anthropic'").unwrap();
+        let provider = crate::providers::configured("sk-ant-test").unwrap();
+        let request = summary_request("Some long text.");
+
+        assert_eq!(
+            "https://api.anthropic.com/v1/messages",
+            provider.endpoint(&request)
+        );
+        let headers = provider.auth_headers().unwrap();
+        assert_eq!("sk-ant-test", headers["x-api-key"]);
+        assert_eq!("2023-06-01", headers["anthropic-version"]);
+
+        let body = provider.request_body(&request);
+        assert_eq!("Be brief.", body["system"]);
+        assert_eq!(
+            "<text>Some long text.</text>",
+            body["messages"][0]["content"]
+        );
+    }
+
+    #[pg_test]
+    fn test_anthropic_response() {
+        Spi::run("SET pg_summarizer.provider = 'anthropic'").unwrap();
+        let provider = crate::providers::configured("sk-ant-test").unwrap();
+        let body = r#"{"content": [{"type": "text", "text": "A short "}, {"type": "text", "text": "summary."}]}"#;
+
+        assert_eq!("A short summary.", provider.parse_response(body).unwrap());
+        assert!(provider.parse_response(r#"{"content": []}"#).is_err());
+    }
+
+    #[pg_test]
+    fn test_ollama_request() {
+        Spi::run("SET pg_summarizer.provider = 'ollama'").unwrap();
+        Spi::run("SET pg_summarizer.ollama_num_ctx = 8192").unwrap();
+        Spi::run("SET pg_summarizer.ollama_keep_alive = '-1'").unwrap();
+        let provider = crate::providers::configured("").unwrap();
+        let request = summary_request("Some long text.");
+
+        assert!(!provider.requires_api_key());
+        assert!(provider.auth_headers().unwrap().is_empty());
+        assert_eq!(
+            "http://localhost:11434/api/chat",
+            provider.endpoint(&request)
+        );
+        let body = provider.request_body(&request);
+        assert_eq!(8192, body["options"]["num_ctx"]);
+        assert_eq!(-1, body["keep_alive"]);
+        assert_eq!(false, body["stream"]);
+    }
+
+    #[pg_test]
+    fn test_ollama_streamed_response() {
+        Spi::run("SET pg_summarizer.provider = 'ollama'").unwrap();
+        Spi::run("SET pg_summarizer.ollama_stream = on").unwrap();
+        let provider = crate::providers::configured("").unwrap();
+        let body = concat!(
+            r#"{"message": {"role": "assistant", "content": "A short "}, "done": false}"#,
+            "\n",
+            r#"{"message": {"role": "assistant", "content": "summary."}, "done": false}"#,
+            "\n",
+            r#"{"message": {"role": "assistant", "content": ""}, "done": true}"#,
+            "\n",
+        );
+
+        assert_eq!("A short summary.", provider.parse_response(body).unwrap());
+    }
+
+    #[pg_test(
+        error = "invalid value for parameter \"pg_summarizer.ollama_keep_alive\": \"5 minutes\""
+    )]
+    fn test_ollama_keep_alive_rejects_bad_duration() {
+        Spi::run("SET pg_summarizer.ollama_keep_alive = '5 minutes'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_azure_request() {
+        Spi::run("SET pg_summarizer.provider = 'azure'").unwrap();
+        Spi::run("SET pg_summarizer.base_url = 'https://contoso.openai.azure.com/'").unwrap();
+        Spi::run("SET pg_summarizer.azure_deployment = 'summaries'").unwrap();
+        let provider = crate::providers::configured("azure-key").unwrap();
+
+        assert_eq!(
+            "https://contoso.openai.azure.com/openai/deployments/summaries/chat/completions?api-version=2024-02-01",
+            provider.endpoint(&summary_request("text"))
+        );
+        let headers = provider.auth_headers().unwrap();
+        assert_eq!("azure-key", headers["api-key"]);
+        assert!(!headers.contains_key("authorization"));
+    }
+
+    #[pg_test]
+    fn test_azure_requires_resource_endpoint() {
+        Spi::run("SET pg_summarizer.provider = 'azure'").unwrap();
+        assert!(crate::providers::configured("azure-key").is_err());
+    }
+
+    #[pg_test]
+    fn test_gemini_request() {
+        Spi::run("SET pg_summarizer.provider = 'gemini'").unwrap();
+        let provider = crate::providers::configured("goog-key").unwrap();
+        let request = summary_request("Some long text.");
+
+        assert_eq!(
+            "https://generativelanguage.googleapis.com/v1beta/models/test-model:generateContent",
+            provider.endpoint(&request)
+        );
+        assert_eq!(
+            "goog-key",
+            provider.auth_headers().unwrap()["x-goog-api-key"]
+        );
+        let body = provider.request_body(&request);
+        assert_eq!("Be brief.", body["systemInstruction"]["parts"][0]["text"]);
+        assert_eq!(
+            "<text>Some long text.</text>",
+            body["contents"][0]["parts"][0]["text"]
+        );
+    }
+
+    #[pg_test]
+    fn test_gemini_response() {
+        Spi::run("SET pg_summarizer.provider = 'gemini'").unwrap();
+        let provider = crate::providers::configured("goog-key").unwrap();
+        let body = r#"{"candidates": [{"content": {"parts": [{"text": "A short summary."}]}, "finishReason": "STOP"}]}"#;
+        let blocked_prompt = r#"{"promptFeedback": {"blockReason": "SAFETY"}}"#;
+        let blocked_answer = r#"{"candidates": [{"finishReason": "SAFETY"}]}"#;
+
+        assert_eq!("A short summary.", provider.parse_response(body).unwrap());
+        assert_eq!(
+            "Gemini blocked the prompt: SAFETY",
+            provider
+                .parse_response(blocked_prompt)
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Gemini returned no text: SAFETY",
+            provider
+                .parse_response(blocked_answer)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[pg_test]
+    fn test_sigv4_known_answer() {
+        use crate::providers::sigv4::{sign, Credentials};
+        use reqwest::header::HeaderMap;
+
+        // Cases of the AWS SigV4 test suite, with their published signatures.
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+        };
+        for (case, method, url, content_type, body, signed_headers, signature) in [
+            (
+                "get-vanilla",
+                "GET",
+                "https://example.amazonaws.com/",
+                None,
+                "",
+                "host;x-amz-date",
+                "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            ),
+            (
+                "get-vanilla-query-order-key-case",
+                "GET",
+                "https://example.amazonaws.com/?Param2=value2&Param1=value1",
+                None,
+                "",
+                "host;x-amz-date",
+                "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+            ),
+            (
+                "post-vanilla",
+                "POST",
+                "https://example.amazonaws.com/",
+                None,
+                "",
+                "host;x-amz-date",
+                "5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b",
+            ),
+            (
+                "post-x-www-form-urlencoded",
+                "POST",
+                "https://example.amazonaws.com/",
+                Some("application/x-www-form-urlencoded"),
+                "Param1=value1",
+                "content-type;host;x-amz-date",
+                "ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a",
+            ),
+        ] {
+            let mut headers = HeaderMap::new();
+            if let Some(content_type) = content_type {
+                headers.insert("content-type", content_type.parse().unwrap());
+            }
+            sign(
+                method,
+                &url.parse().unwrap(),
+                &mut headers,
+                body.as_bytes(),
+                &credentials,
+                "us-east-1",
+                "service",
+                "20150830T123600Z",
+            )
+            .unwrap();
+
+            assert_eq!(
+                format!(
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                    SignedHeaders={}, Signature={}",
+                    signed_headers, signature
+                ),
+                headers["authorization"],
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[pg_test]
+    fn test_bedrock_signed_request() {
+        // The signature itself is checked against the AWS test suite in
+        // test_sigv4_known_answer; this checks what Bedrock requests sign.
+        let base_url = stand_in_server(|request| {
+            let amz_date = request.header("x-amz-date");
+            let expected = format!(
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/{}/us-west-2/bedrock/aws4_request, \
+                SignedHeaders=content-type;host;x-amz-date, Signature=",
+                amz_date.get(..8).unwrap_or_default()
+            );
+            let authorization = request.header("authorization");
+            let signature = authorization.strip_prefix(&expected).unwrap_or_default();
+            let signed = amz_date.len() == 16
+                && signature.len() == 64
+                && signature.bytes().all(|b| b.is_ascii_hexdigit());
+            let path_matches =
+                request.path == "/model/anthropic.claude-3-haiku-20240307-v1%3A0/converse";
+            if signed && path_matches {
+                let body = r#"{"output": {"message": {"role": "assistant", "content": [{"text": "A short summary."}]}}, "stopReason": "end_turn"}"#;
+                (200, body.to_string())
+            } else {
+                (
+                    403,
+                    r#"{"message": "The request signature we calculated does not match"}"#
+                        .to_string(),
+                )
+            }
+        });
+
+        Spi::run("SET pg_summarizer.provider = 'bedrock'").unwrap();
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.aws_region = 'us-west-2'").unwrap();
+        Spi::run("SET pg_summarizer.aws_access_key_id = 'AKIDEXAMPLE'").unwrap();
+        Spi::run("SET pg_summarizer.aws_secret_access_key = 'bedrock-secret'").unwrap();
+
+        assert_eq!(
+            Ok(Some("A short summary.".to_string())),
+            Spi::get_one::<String>("SELECT summarize('Some long text.')")
+        );
+    }
+
+    #[pg_test]
+    fn test_settings_are_registered() {
+        let context = |name: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT context FROM pg_settings WHERE name = '{}'",
+                name
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!("superuser", context("pg_summarizer.api_key"));
+        assert_eq!("user", context("pg_summarizer.model"));
+        assert_eq!("user", context("pg_summarizer.prompt"));
+        assert_eq!(
+            Ok(Some(
+                crate::guc::DEFAULT_PROMPT.to_str().unwrap().to_string()
+            )),
+            Spi::get_one::<String>("SHOW pg_summarizer.prompt")
+        );
+    }
+
+    #[pg_test(error = "pg_summarizer.api_key is not set")]
+    fn test_missing_api_key() {
+        Spi::run("SELECT summarize('Some long text.')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_error_sqlstates() {
+        let base_url = stand_in_server(|request| {
+            match request.header("authorization") {
+            "Bearer rate-limited" => (429, r#"{"error": {"message": "Rate limit reached"}}"#.into()),
+            "Bearer malformed" => (200, r#"{"choices": []}"#.into()),
+            "Bearer refused" => (
+                200,
+                r#"{"choices": [{"message": {"content": null}, "finish_reason": "content_filter"}]}"#
+                    .into(),
+            ),
+            _ => (401, r#"{"error": {"message": "Incorrect API key provided"}}"#.into()),
+        }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.max_attempts = 1").unwrap();
+
+        assert_eq!("28000", sqlstate_of("SET LOCAL pg_summarizer.api_key = ''"));
+        assert_eq!(
+            "53400",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'rate-limited'")
+        );
+        assert_eq!(
+            "08P01",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'malformed'")
+        );
+        assert_eq!(
+            "38000",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'refused'")
+        );
+        assert_eq!(
+            "39000",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'wrong'")
+        );
+
+        Spi::run("SET pg_summarizer.base_url = 'http://127.0.0.1:1'").unwrap();
+        assert_eq!(
+            "08001",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'sk-test'")
+        );
+    }
+
+    #[pg_test]
+    fn test_on_error_modes() {
+        let base_url = stand_in_server(|request| {
+            if String::from_utf8_lossy(&request.body).contains("poison") {
+                (
+                    500,
+                    r#"{"error": {"message": "The server had an error"}}"#.into(),
+                )
+            } else {
+                (
+                    200,
+                    r#"{"choices": [{"message": {"content": "A summary."}}]}"#.into(),
+                )
+            }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.max_attempts = 1").unwrap();
+
+        let summarized =
+            "SELECT count(summarize(t)) FROM (VALUES ('good'), ('poison'), ('fine')) v(t)";
+        Spi::run("SET pg_summarizer.on_error = 'null'").unwrap();
+        assert_eq!(Ok(Some(2)), Spi::get_one::<i64>(summarized));
+        Spi::run("SET pg_summarizer.on_error = 'warning'").unwrap();
+        assert_eq!(Ok(Some(2)), Spi::get_one::<i64>(summarized));
+
+        Spi::run("SET pg_summarizer.on_error = 'placeholder'").unwrap();
+        Spi::run("SET pg_summarizer.on_error_placeholder = '[no summary]'").unwrap();
+        assert_eq!(
+            Ok(Some("[no summary]".to_string())),
+            Spi::get_one::<String>("SELECT summarize('poison')")
+        );
+
+        // The argument overrides the setting for a single call.
+        assert_eq!(
+            Ok(None),
+            Spi::get_one::<String>("SELECT summarize('poison', on_error => 'null')")
+        );
+        assert_eq!(
+            "39000",
+            sqlstate_of(
+                "SET LOCAL pg_summarizer.on_error = 'null'; PERFORM summarize('poison', on_error => 'error')"
+            )
+        );
+    }
+
+    #[pg_test(error = "permission denied to set parameter \"pg_summarizer.base_url\"")]
+    fn test_base_url_requires_superuser() {
+        // Otherwise any role could have the API key sent to a server of its own.
+        Spi::run("CREATE ROLE pg_summarize_test_user; SET ROLE pg_summarize_test_user").unwrap();
+        Spi::run("SET pg_summarizer.base_url = 'http://attacker.example'").unwrap();
+    }
+
+    #[pg_test(error = "invalid value for parameter \"pg_summarizer.aws_region\": \"x@evil.com#\"")]
+    fn test_aws_region_rejects_host_names() {
+        // The region is part of the host signed requests are sent to.
+        Spi::run("SET pg_summarizer.aws_region = 'x@evil.com#'").unwrap();
+    }
+
+    #[pg_test(error = "permission denied to set parameter \"pg_summarizer.aws_region\"")]
+    fn test_aws_region_requires_superuser() {
+        Spi::run("CREATE ROLE pg_summarize_test_user; SET ROLE pg_summarize_test_user").unwrap();
+        Spi::run("SET pg_summarizer.aws_region = 'eu-west-1'").unwrap();
+    }
+
+    #[pg_test(error = "permission denied to set parameter \"pg_summarizer.provider\"")]
+    fn test_provider_requires_superuser() {
+        Spi::run("CREATE ROLE pg_summarize_test_user; SET ROLE pg_summarize_test_user").unwrap();
+        Spi::run("SET pg_summarizer.provider = 'gemini'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_null_input() {
+        // NULL rows give NULL, as from a strict function, whatever on_error
+        // is; nothing is sent or queued for them.
+        Spi::run("SET pg_summarizer.on_error = 'error'").unwrap();
+        Spi::run("CREATE TABLE texts (body text); INSERT INTO texts VALUES (NULL)").unwrap();
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM texts WHERE summarize(body) IS NULL \
+                AND summarize_async(body) IS NULL AND summarize_token_count(body) IS NULL"
+            )
+        );
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT count(*) FROM summarize_jobs")
+        );
+    }
+
+    #[pg_test(error = "invalid on_error mode \"sometimes\"")]
+    fn test_on_error_rejects_unknown_mode() {
+        Spi::run("SELECT summarize('Some long text.', on_error => 'sometimes')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_retries_transient_failures() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base_url = stand_in_server_with_headers(move |request| {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            let error = r#"{"error": {"message": "Try again"}}"#.to_string();
+            match request.header("authorization") {
+                "Bearer flaky" if n == 0 => (503, vec![], error),
+                "Bearer flaky" if n == 1 => (429, vec![("Retry-After", "0".into())], error),
+                "Bearer flaky" => (
+                    200,
+                    vec![],
+                    r#"{"choices": [{"message": {"content": "A summary."}}]}"#.into(),
+                ),
+                "Bearer slow-down" => (429, vec![("Retry-After", "3600".into())], error),
+                _ => (400, vec![], error),
+            }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.retry_base_delay = '1ms'").unwrap();
+
+        Spi::run("SET pg_summarizer.api_key = 'flaky'").unwrap();
+        assert_eq!(
+            Ok(Some("A summary.".to_string())),
+            Spi::get_one::<String>("SELECT summarize('Some long text.')")
+        );
+        assert_eq!(3, requests.swap(0, Ordering::SeqCst));
+
+        // Client errors are not retried.
+        assert_eq!(
+            "39000",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'bad-request'")
+        );
+        assert_eq!(1, requests.swap(0, Ordering::SeqCst));
+
+        // Neither is a rate limit that lifts only after pg_summarizer.retry_max_time.
+        assert_eq!(
+            "53400",
+            sqlstate_of("SET LOCAL pg_summarizer.api_key = 'slow-down'")
+        );
+        assert_eq!(1, requests.swap(0, Ordering::SeqCst));
+    }
+
+    #[pg_test]
+    fn test_retry_after_headers() {
+        use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+        use std::time::{Duration, SystemTime};
+
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in pairs {
+                map.insert(
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                );
+            }
+            crate::retry::retry_after(
+                &map,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(784111717),
+            )
+        };
+
+        assert_eq!(None, headers(&[]));
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            headers(&[("retry-after", "120")])
+        );
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            headers(&[("retry-after", "Sun, 06 Nov 1994 08:49:37 GMT")])
+        );
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            headers(&[("retry-after-ms", "250"), ("retry-after", "1")])
+        );
+        assert_eq!(
+            Some(Duration::from_secs(360)),
+            headers(&[
+                ("x-ratelimit-remaining-requests", "12"),
+                ("x-ratelimit-reset-requests", "20ms"),
+                ("x-ratelimit-remaining-tokens", "0"),
+                ("x-ratelimit-reset-tokens", "6m0s"),
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_connections_are_reused() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Instant;
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let seen = connections.clone();
+        let base_url = stand_in_server(move |request| {
+            seen.fetch_max(request.connection + 1, Ordering::SeqCst);
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "A summary."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+
+        let calls = 200;
+        let backfill = format!(
+            "SELECT count(summarize('Some long text.')) FROM generate_series(1, {})",
+            calls
+        );
+        let run = |pool_size: i32| {
+            Spi::run(&format!(
+                "SET pg_summarizer.pool_max_idle_per_host = {}",
+                pool_size
+            ))
+            .unwrap();
+            let before = connections.load(Ordering::SeqCst);
+            let started = Instant::now();
+            assert_eq!(Ok(Some(calls)), Spi::get_one::<i64>(&backfill));
+            (
+                connections.load(Ordering::SeqCst) - before,
+                started.elapsed(),
+            )
+        };
+
+        // Changing a pool setting replaces the client, so each run starts cold.
+        let (fresh_connections, fresh_time) = run(0);
+        let (pooled_connections, pooled_time) = run(4);
+        notice!(
+            "{} calls: {:?} with a new connection each, {:?} pooled",
+            calls,
+            fresh_time,
+            pooled_time
+        );
+        assert_eq!(calls as usize, fresh_connections);
+        assert_eq!(1, pooled_connections);
+    }
+
+    #[pg_test]
+    fn test_request_timeout() {
+        let base_url = stand_in_server(|_| {
+            std::thread::sleep(std::time::Duration::from_secs(5));
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "Too late."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.max_attempts = 1").unwrap();
+
+        let started = std::time::Instant::now();
+        assert_eq!(
+            "08006",
+            sqlstate_of("SET LOCAL pg_summarizer.request_timeout = '100ms'")
+        );
+        assert!(started.elapsed() < std::time::Duration::from_secs(4));
+    }
+
+    #[pg_test(error = "canceling statement due to user request")]
+    fn test_cancel_interrupts_request() {
+        // Deliver the same SIGINT as pg_cancel_backend() once the request is in flight.
+        let backend = std::process::id().to_string();
+        let base_url = stand_in_server(move |_| {
+            std::process::Command::new("kill")
+                .args(["-INT", &backend])
+                .status()
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_secs(30));
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "Too late."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.request_timeout = 0").unwrap();
+        Spi::run("SELECT summarize('Some long text.')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_summary_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base_url = stand_in_server(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "A summary."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.cache = on").unwrap();
+
+        let summarize = |sql: &str| Spi::get_one::<String>(sql).unwrap().unwrap();
+        assert_eq!(
+            "A summary.",
+            summarize("SELECT summarize('Some long text.')")
+        );
+        assert_eq!(
+            "A summary.",
+            summarize("SELECT summarize(E'  Some long\\n text. ')")
+        );
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        // A different model is a different entry.
+        Spi::run("SET pg_summarizer.model = 'gpt-4o'").unwrap();
+        summarize("SELECT summarize('Some long text.')");
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+
+        assert_eq!(
+            Ok((Some(2), Some(1))),
+            Spi::get_two::<i64, i64>("SELECT entries, session_hits FROM summarize_cache_stats()")
+        );
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT summarize_cache_purge(true)")
+        );
+        assert_eq!(
+            Ok(Some(2)),
+            Spi::get_one::<i64>("SELECT summarize_cache_purge()")
+        );
+        summarize("SELECT summarize('Some long text.')");
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+    }
+
+    #[pg_test]
+    fn test_shared_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base_url = stand_in_server(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "A shared summary."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.cache = on").unwrap();
+
+        let hits = || {
+            Spi::get_one::<i64>("SELECT hits FROM summarize_shared_cache")
+                .unwrap()
+                .unwrap()
+        };
+        let hits_before = hits();
+        let summarize = "SELECT summarize('Text for the shared cache.')";
+        assert_eq!(
+            Ok(Some("A shared summary.".into())),
+            Spi::get_one::<String>(summarize)
+        );
+
+        // Hits served from shared memory count in the table too, so its least
+        // recently used entries are still the ones evicted.
+        Spi::get_one::<String>(summarize).unwrap();
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>("SELECT hits FROM summarize_cache")
+        );
+
+        // Served from shared memory even once the table entry is gone.
+        Spi::run("DELETE FROM summarize_cache").unwrap();
+        assert_eq!(
+            Ok(Some("A shared summary.".into())),
+            Spi::get_one::<String>(summarize)
+        );
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+        assert_eq!(hits_before + 2, hits());
+        assert_eq!(
+            Ok(Some(true)),
+            Spi::get_one::<bool>("SELECT used > 0 AND used <= slots FROM summarize_shared_cache")
+        );
+    }
+
+    #[pg_test]
+    fn test_summarize_async() {
+        let base_url = stand_in_server(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            if body["messages"][1]["content"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("poison")
+            {
+                (400, r#"{"error": {"message": "Bad input"}}"#.into())
+            } else {
+                let reply = format!(
+                    "Summary by {} at {}.",
+                    body["model"].as_str().unwrap(),
+                    body["temperature"]
+                );
+                let choices = serde_json::json!({"choices": [{"message": {"content": reply}}]});
+                (200, choices.to_string())
+            }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.model = 'queued-model'").unwrap();
+        Spi::run("SET pg_summarizer.temperature = 0.3").unwrap();
+
+        let good = Spi::get_one::<i64>("SELECT summarize_async('Some long text.')")
+            .unwrap()
+            .unwrap();
+        let bad = Spi::get_one::<i64>("SELECT summarize_async('poison', model => 'other')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Ok(None),
+            Spi::get_one::<String>(&format!("SELECT summarize_result({})", good))
+        );
+
+        // The job keeps the model and settings of the session that queued it.
+        Spi::run("SET pg_summarizer.model = 'worker-model'").unwrap();
+        Spi::run("RESET pg_summarizer.temperature").unwrap();
+        assert_eq!(
+            Ok(Some(2)),
+            Spi::get_one::<i32>("SELECT summarize_run_jobs()")
+        );
+        assert_eq!(
+            Ok(Some("Summary by queued-model at 0.3.".to_string())),
+            Spi::get_one::<String>(&format!("SELECT summarize_result({})", good))
+        );
+        // And leaves the settings of this one as they were.
+        assert_eq!(
+            Ok(Some("Summary by worker-model at null.".to_string())),
+            Spi::get_one::<String>("SELECT summarize('Some long text.')")
+        );
+        assert_eq!(
+            Ok(Some("failed".to_string())),
+            Spi::get_one::<String>(&format!(
+                "SELECT status FROM summarize_jobs WHERE id = {}",
+                bad
+            ))
+        );
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i32>("SELECT summarize_run_jobs()")
+        );
+    }
+
+    #[pg_test(error = "summarization job 42 does not exist")]
+    fn test_summarize_result_unknown_job() {
+        Spi::run("SELECT summarize_result(42)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_summarize_register() {
+        let base_url = stand_in_server(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let text = body["messages"][1]["content"].as_str().unwrap_or_default();
+            let reply = if text.contains("second") {
+                "Second summary."
+            } else {
+                "First summary."
+            };
+            let choices = serde_json::json!({"choices": [{"message": {"content": reply}}]});
+            (200, choices.to_string())
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run(
+            "CREATE TABLE articles (id int PRIMARY KEY, body text, body_summary text); \
+            INSERT INTO articles VALUES (1, 'Existing text.', NULL)",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>(
+                "SELECT summarize_register('articles', source => 'body', \
+                target => 'body_summary', backfill => true)"
+            )
+        );
+        Spi::run("INSERT INTO articles VALUES (2, 'New text.', 'Stale summary.')").unwrap();
+        // Jobs only queue the summary; the row is written when they run.
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT count(body_summary) FROM articles")
+        );
+        assert_eq!(
+            Ok(Some(2)),
+            Spi::get_one::<i32>("SELECT summarize_run_jobs()")
+        );
+        let summaries = "SELECT string_agg(body_summary, ' ' ORDER BY id) FROM articles";
+        assert_eq!(
+            Ok(Some("First summary. First summary.".to_string())),
+            Spi::get_one::<String>(summaries)
+        );
+
+        // Updating other columns, or setting the same text, queues nothing.
+        Spi::run("UPDATE articles SET body = body, body_summary = body_summary").unwrap();
+        Spi::run("UPDATE articles SET body = 'The second text.' WHERE id = 2").unwrap();
+        assert_eq!(
+            Ok(Some("First summary.".to_string())),
+            Spi::get_one::<String>(summaries)
+        );
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i32>("SELECT summarize_run_jobs()")
+        );
+        assert_eq!(
+            Ok(Some("First summary. Second summary.".to_string())),
+            Spi::get_one::<String>(summaries)
+        );
+
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>("SELECT summarize_unregister('articles')")
+        );
+        Spi::run("INSERT INTO articles VALUES (3, 'Unwatched text.', NULL)").unwrap();
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i32>("SELECT summarize_run_jobs()")
+        );
+    }
+
+    #[pg_test]
+    fn test_write_back_requires_registration() {
+        let base_url = stand_in_server(|_| {
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "Overwritten."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run(
+            "CREATE TABLE accounts (id int PRIMARY KEY, note text, secret text); \
+            INSERT INTO accounts VALUES (1, 'A note.', 'Kept.')",
+        )
+        .unwrap();
+
+        // A job naming a column nobody registered is not written back.
+        Spi::run(
+            "INSERT INTO summarize_jobs \
+            (input, target_relation, source_column, target_column, target_key) \
+            VALUES ('A note.', 'accounts', 'note', 'secret', '{\"id\": 1}')",
+        )
+        .unwrap();
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i32>("SELECT summarize_run_jobs()")
+        );
+        assert_eq!(
+            Ok(Some("Kept.".to_string())),
+            Spi::get_one::<String>("SELECT secret FROM accounts WHERE id = 1")
+        );
+        assert_eq!(
+            Ok(Some(
+                "column secret of relation accounts is not registered for summaries".to_string()
+            )),
+            Spi::get_one::<String>("SELECT error FROM summarize_jobs WHERE status = 'failed'")
+        );
+    }
+
+    #[pg_test(error = "relation \"keyless\" has no primary key")]
+    fn test_summarize_register_needs_primary_key() {
+        Spi::run("CREATE TABLE keyless (body text, body_summary text)").unwrap();
+        Spi::run("SELECT summarize_register('keyless', 'body', 'body_summary')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_summarize_batch() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let (current, most) = (in_flight.clone(), most_in_flight.clone());
+        let base_url = stand_in_server(move |request| {
+            let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            current.fetch_sub(1, Ordering::SeqCst);
+
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let text = body["messages"][1]["content"].as_str().unwrap_or_default();
+            if text.contains("poison") {
+                return (400, r#"{"error": {"message": "Bad input"}}"#.into());
+            }
+            let reply = text
+                .replace("<text>", "Summary of ")
+                .replace("</text>", ".");
+            let choices = serde_json::json!({"choices": [{"message": {"content": reply}}]});
+            (200, choices.to_string())
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+
+        let summaries = Spi::get_one::<Vec<Option<String>>>(
+            "SELECT summarize_batch(ARRAY(SELECT 'text ' || i FROM generate_series(1, 12) i), \
+            parallelism => 4)",
+        )
+        .unwrap()
+        .unwrap();
+        let expected: Vec<_> = (1..=12)
+            .map(|i| Some(format!("Summary of text {}.", i)))
+            .collect();
+        assert_eq!(expected, summaries);
+        assert_eq!(4, most_in_flight.load(Ordering::SeqCst));
+
+        // Failures and NULLs stay in their place.
+        assert_eq!(
+            Ok(Some(vec![
+                Some("Summary of one.".to_string()),
+                None,
+                None,
+                Some("Summary of four.".to_string()),
+            ])),
+            Spi::get_one::<Vec<Option<String>>>(
+                "SELECT summarize_batch(ARRAY['one', NULL, 'poison', 'four'], 'null')"
+            )
+        );
+        assert_eq!(
+            Ok(Some(
+                "2:39000:provider request failed with status 400 Bad Request".to_string()
+            )),
+            Spi::get_one::<String>(
+                "SELECT string_agg(ordinality || ':' || coalesce(sqlstate, '') || ':' \
+                || coalesce(error, summary), ', ') \
+                FROM summarize_batch_rows(ARRAY['one', 'poison']) WHERE error IS NOT NULL"
+            )
+        );
+    }
+
+    #[pg_test(error = "parallelism must be between 1 and 256, not 100000")]
+    fn test_summarize_batch_rejects_too_much_parallelism() {
+        Spi::run("SELECT summarize_batch(ARRAY['Some long text.'], parallelism => 100000)")
+            .unwrap();
+    }
+
+    #[pg_test]
+    fn test_openai_batch() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        // Stands in for the files and batches endpoints: the batch completes
+        // on the second check, and the request for key 3 fails.
+        let uploaded = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let checks = Arc::new(AtomicUsize::new(0));
+        let sent = uploaded.clone();
+        let base_url = stand_in_server(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/files") => {
+                    let body = String::from_utf8_lossy(&request.body).into_owned();
+                    assert!(body.contains("name=\"purpose\"\r\n\r\nbatch"));
+                    *uploaded.lock().unwrap() = body
+                        .lines()
+                        .filter(|line| line.starts_with('{'))
+                        .map(|line| serde_json::from_str(line).unwrap())
+                        .collect();
+                    (200, r#"{"id": "file-in"}"#.into())
+                }
+                ("POST", "/batches") => {
+                    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    assert_eq!("file-in", body["input_file_id"]);
+                    assert_eq!("/v1/chat/completions", body["endpoint"]);
+                    (200, r#"{"id": "batch_1", "status": "validating"}"#.into())
+                }
+                ("GET", "/batches/batch_1") => {
+                    let batch = if checks.fetch_add(1, Ordering::SeqCst) == 0 {
+                        serde_json::json!({"id": "batch_1", "status": "in_progress"})
+                    } else {
+                        serde_json::json!({
+                            "id": "batch_1",
+                            "status": "completed",
+                            "output_file_id": "file-out",
+                            "request_counts": {"total": 3, "completed": 2, "failed": 1}
+                        })
+                    };
+                    (200, batch.to_string())
+                }
+                ("GET", "/files/file-out/content") => {
+                    let lines: Vec<String> = uploaded
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|line| {
+                            let id = line["custom_id"].as_str().unwrap();
+                            let response = if id == "3" {
+                                serde_json::json!({"status_code": 400, "body": {}})
+                            } else {
+                                let model = line["body"]["model"].as_str().unwrap();
+                                let reply = format!("Summary {} by {}.", id, model);
+                                serde_json::json!({"status_code": 200, "body": {
+                                    "choices": [{"message": {"content": reply}}]
+                                }})
+                            };
+                            serde_json::json!({"custom_id": id, "response": response}).to_string()
+                        })
+                        .collect();
+                    (200, lines.join("\n"))
+                }
+                _ => (404, r#"{"error": {"message": "Not found"}}"#.into()),
+            }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.model = 'batch-model'").unwrap();
+        Spi::run("SET pg_summarizer.format = 'bullets'").unwrap();
+        Spi::run(
+            "CREATE TABLE docs (doc_id int PRIMARY KEY, body text, summary text); \
+            INSERT INTO docs VALUES (1, 'One.'), (2, 'Two.'), (3, 'Three.')",
+        )
+        .unwrap();
+
+        let id = Spi::get_one::<i64>(
+            "SELECT summarize_openai_batch_submit('SELECT doc_id, body FROM docs', 'docs', \
+            key_column => 'doc_id')",
+        )
+        .unwrap()
+        .unwrap();
+        // Requests carry the same style instructions summarize() would send.
+        let system = sent.lock().unwrap()[0]["body"]["messages"][0]["content"].clone();
+        assert!(system
+            .as_str()
+            .unwrap()
+            .contains("Write the summary as a list of bullet points"));
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT summarize_openai_batch_poll()")
+        );
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>("SELECT summarize_openai_batch_poll()")
+        );
+        assert_eq!(
+            Ok(Some(
+                "Summary 1 by batch-model., Summary 2 by batch-model.".to_string()
+            )),
+            Spi::get_one::<String>("SELECT string_agg(summary, ', ' ORDER BY doc_id) FROM docs")
+        );
+        assert_eq!(
+            Ok(Some("completed 3 2 1 2".to_string())),
+            Spi::get_one::<String>(&format!(
+                "SELECT concat_ws(' ', status, request_count, completed_count, failed_count, \
+                written_count) FROM summarize_openai_batches WHERE id = {} \
+                AND finished_at IS NOT NULL",
+                id
+            ))
+        );
+        // Ended batches are not checked again.
+        assert_eq!(
+            Ok(Some(0)),
+            Spi::get_one::<i64>("SELECT summarize_openai_batch_poll()")
+        );
+    }
+
+    #[pg_test]
+    fn test_openai_batch_split() {
+        let requests: Vec<String> = ["a", "bb", "ccc", "d", "e"]
+            .iter()
+            .map(|request| request.to_string())
+            .collect();
+        let sizes = |files: Vec<&[String]>| files.iter().map(|f| f.len()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![5],
+            sizes(crate::openai_batch::split_requests(&requests, 5, 100))
+        );
+        assert_eq!(
+            vec![2, 2, 1],
+            sizes(crate::openai_batch::split_requests(&requests, 2, 100))
+        );
+        // "a\nbb\n" fits in 5 bytes, "ccc\n" does not fit with "d\n".
+        assert_eq!(
+            vec![2, 1, 2],
+            sizes(crate::openai_batch::split_requests(&requests, 5, 5))
+        );
+        assert_eq!(
+            vec![1, 1, 1, 1, 1],
+            sizes(crate::openai_batch::split_requests(&requests, 5, 2))
+        );
+    }
+
+    #[pg_test]
+    fn test_openai_batch_deletes_file_of_batch_not_started() {
+        use std::sync::{Arc, Mutex};
+
+        let deleted = Arc::new(Mutex::new(Vec::<String>::new()));
+        let seen = deleted.clone();
+        let base_url = stand_in_server(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/files") => (200, r#"{"id": "file-in"}"#.into()),
+                ("DELETE", path) => {
+                    seen.lock().unwrap().push(path.to_string());
+                    (200, r#"{"deleted": true}"#.into())
+                }
+                _ => (400, r#"{"error": {"message": "Too many batches"}}"#.into()),
+            }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("CREATE TABLE notes (id int PRIMARY KEY, body text, summary text)").unwrap();
+        Spi::run("INSERT INTO notes VALUES (1, 'One.')").unwrap();
+
+        assert_eq!(
+            "39000",
+            sqlstate_of(
+                "PERFORM summarize_openai_batch_submit('SELECT id, body FROM notes', 'notes')"
+            )
+        );
+        assert_eq!(vec!["/files/file-in"], *deleted.lock().unwrap());
+    }
+
+    #[pg_test]
+    fn test_openai_batch_records_write_back_failure() {
+        let base_url = stand_in_server(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/files") => (200, r#"{"id": "file-in"}"#.into()),
+                ("POST", "/batches") => (200, r#"{"id": "batch_2", "status": "validating"}"#.into()),
+                ("GET", "/batches/batch_2") => (
+                    200,
+                    r#"{"id": "batch_2", "status": "completed", "output_file_id": "file-out"}"#
+                        .into(),
+                ),
+                ("GET", "/files/file-out/content") => (
+                    200,
+                    r#"{"custom_id": "1", "response": {"status_code": 200, "body": {"choices": [{"message": {"content": "Summary."}}]}}}"#
+                        .into(),
+                ),
+                _ => (404, r#"{"error": {"message": "Not found"}}"#.into()),
+            }
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("CREATE TABLE notes (id int PRIMARY KEY, body text, summary text)").unwrap();
+        Spi::run("INSERT INTO notes VALUES (1, 'One.')").unwrap();
+        Spi::run("SELECT summarize_openai_batch_submit('SELECT id, body FROM notes', 'notes')")
+            .unwrap();
+
+        // The batch still ends, with the reason its summaries were not written.
+        Spi::run("ALTER TABLE notes DROP COLUMN summary").unwrap();
+        assert_eq!(
+            Ok(Some(1)),
+            Spi::get_one::<i64>("SELECT summarize_openai_batch_poll()")
+        );
+        assert_eq!(
+            Ok(Some(
+                "0 column \"summary\" of relation \"notes\" does not exist".to_string()
+            )),
+            Spi::get_one::<String>(
+                "SELECT concat_ws(' ', written_count, error) FROM summarize_openai_batches \
+                WHERE batch_id = 'batch_2' AND finished_at IS NOT NULL"
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_long_text_strategies() {
+        use std::sync::{Arc, Mutex};
+
+        // Answers part requests with the first word of the part, and combine
+        // and refine requests with a fixed summary.
+        let requests = Arc::new(Mutex::new(Vec::<(String, String)>::new()));
+        let seen = requests.clone();
+        let base_url = stand_in_server(move |request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let prompt = body["messages"][0]["content"].as_str().unwrap().to_string();
+            let text = body["messages"][1]["content"].as_str().unwrap().to_string();
+            let reply = if prompt.contains("Combine them") {
+                "Combined.".to_string()
+            } else if prompt.contains("<summary> tags") {
+                "Refined.".to_string()
+            } else {
+                text.trim_start_matches("<text>")
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            seen.lock().unwrap().push((prompt, text));
+            let choices = serde_json::json!({"choices": [{"message": {"content": reply}}]});
+            (200, choices.to_string())
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.max_input_tokens = 50").unwrap();
+        Spi::run("SET pg_summarizer.chunk_overlap = 10").unwrap();
+        let long_text = "SELECT summarize(string_agg('Sentence ' || i || ' is here.', ' ')) \
+            FROM generate_series(1, 60) i";
+
+        assert_eq!(
+            Ok(Some("Combined.".to_string())),
+            Spi::get_one::<String>(long_text)
+        );
+        {
+            let requests = requests.lock().unwrap();
+            let parts = requests
+                .iter()
+                .filter(|(prompt, _)| prompt.contains("one part of a longer document"))
+                .count();
+            assert!(parts > 5, "{} parts", parts);
+            let tokenizer = crate::tokens::Tokenizer::for_model("gpt-3.5-turbo");
+            assert!(requests.iter().all(|(_, text)| {
+                let text = text
+                    .trim_start_matches("<text>")
+                    .trim_end_matches("</text>");
+                tokenizer.count(text) <= 50
+            }));
+        }
+
+        // An overlap close to the chunk size is held to half of it, so the
+        // chunks still move forward quickly.
+        requests.lock().unwrap().clear();
+        Spi::run("SET pg_summarizer.chunk_overlap = 49").unwrap();
+        Spi::get_one::<String>(long_text).unwrap();
+        let parts = requests.lock().unwrap().len();
+        assert!(parts < 20, "{} requests", parts);
+        Spi::run("SET pg_summarizer.chunk_overlap = 10").unwrap();
+
+        requests.lock().unwrap().clear();
+        Spi::run("SET pg_summarizer.long_text_strategy = 'refine'").unwrap();
+        assert_eq!(
+            Ok(Some("Refined.".to_string())),
+            Spi::get_one::<String>(long_text)
+        );
+        {
+            let requests = requests.lock().unwrap();
+            assert!(requests.len() > 5);
+            assert!(requests[1]
+                .1
+                .starts_with("<text><summary>Sentence</summary>"));
+            assert!(requests[2]
+                .1
+                .starts_with("<text><summary>Refined.</summary>"));
+        }
+
+        requests.lock().unwrap().clear();
+        Spi::run("SET pg_summarizer.long_text_strategy = 'truncate'").unwrap();
+        assert_eq!(
+            Ok(Some("Sentence".to_string())),
+            Spi::get_one::<String>(long_text)
+        );
+        assert_eq!(
+            "<text>Sentence 1 is here. Sentence 2 is here. Sentence 3 is here. \
+            Sentence 4 is here. Sentence 5 is here. Sentence 6 is here. Sentence 7 is here. \
+            Sentence 8 is here. </text>",
+            requests.lock().unwrap()[0].1
+        );
+
+        Spi::run("SET pg_summarizer.long_text_strategy = 'error'").unwrap();
+        assert_eq!(
+            "54000",
+            sqlstate_of("SET LOCAL pg_summarizer.max_input_tokens = 1")
+        );
+    }
+
+    #[pg_test]
+    fn test_long_input_without_room_in_context_window() {
+        let base_url = stand_in_server(|_| panic!("a chunk too small to summarize was sent"));
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        // An answer of 8000 tokens leaves gpt-4 almost no room for the input.
+        assert_eq!(
+            "54000",
+            sqlstate_of(
+                "SET LOCAL pg_summarizer.model = 'gpt-4'; \
+                PERFORM summarize(repeat(' word.', 200), max_tokens => 8000)"
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_summarize_token_count() {
+        assert_eq!(
+            Ok(Some(2)),
+            Spi::get_one::<i64>("SELECT summarize_token_count('hello world', 'gpt-4o')")
+        );
+        assert_eq!(
+            Ok(Some(9)),
+            Spi::get_one::<i64>("SELECT summarize_token_count('日本語のテキストです', 'gpt-4')")
+        );
+        assert_eq!(
+            Ok(Some(7)),
+            Spi::get_one::<i64>(
+                "SELECT summarize_token_count('日本語のテキストです', 'o200k_base')"
+            )
+        );
+        // Without a model, the configured one is used.
+        Spi::run("SET pg_summarizer.model = 'gpt-4o-mini'").unwrap();
+        assert_eq!(
+            Ok(Some(7)),
+            Spi::get_one::<i64>("SELECT summarize_token_count('日本語のテキストです')")
+        );
+    }
+
+    #[pg_test]
+    fn test_input_over_context_window() {
+        let base_url = stand_in_server(|_| panic!("an input that cannot fit was sent"));
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.long_text_strategy = 'error'").unwrap();
+        // gpt-4 takes 8192 tokens; each repetition is two.
+        assert_eq!(
+            "54000",
+            sqlstate_of("SET LOCAL pg_summarizer.model = 'gpt-4'; PERFORM summarize(repeat(' word.', 5000))")
+        );
+    }
+
+    #[pg_test]
+    fn test_context_windows() {
+        use crate::tokens::context_window;
+
+        assert_eq!(Some(8_192), context_window("gpt-4"));
+        assert_eq!(Some(8_192), context_window("gpt-4-0613"));
+        assert_eq!(Some(128_000), context_window("gpt-4-turbo-2024-04-09"));
+        assert_eq!(Some(128_000), context_window("gpt-4.5-preview"));
+        assert_eq!(Some(1_047_576), context_window("gpt-4.1-mini"));
+        assert_eq!(None, context_window("llama3"));
+    }
+
+    #[pg_test]
+    fn test_generation_params() {
+        use std::sync::{Arc, Mutex};
+
+        let bodies = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let seen = bodies.clone();
+        let base_url = stand_in_server(move |request| {
+            seen.lock()
+                .unwrap()
+                .push(serde_json::from_slice(&request.body).unwrap());
+            (
+                200,
+                r#"{"choices": [{"message": {"content": "Short."}}]}"#.into(),
+            )
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.temperature = '0.2'").unwrap();
+        Spi::run("SET pg_summarizer.seed = '42'").unwrap();
+        Spi::run(r#"SET pg_summarizer.stop = '["---", "END"]'"#).unwrap();
+
+        Spi::run("SELECT summarize('Some long text.')").unwrap();
+        Spi::run("SELECT summarize('Some long text.', temperature => 0.7, max_tokens => 50)")
+            .unwrap();
+        Spi::run("SET pg_summarizer.model = 'o3-mini'").unwrap();
+        Spi::run("SELECT summarize('Some long text.', stop => ARRAY[]::text[], max_tokens => 50)")
+            .unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(0.2, bodies[0]["temperature"]);
+        assert_eq!(42, bodies[0]["seed"]);
+        assert_eq!(serde_json::json!(["---", "END"]), bodies[0]["stop"]);
+        assert!(bodies[0].get("max_tokens").is_none());
+        assert!(bodies[0].get("top_p").is_none());
+        assert_eq!(0.7, bodies[1]["temperature"]);
+        assert_eq!(50, bodies[1]["max_tokens"]);
+        assert_eq!(42, bodies[1]["seed"]);
+        assert_eq!(50, bodies[2]["max_completion_tokens"]);
+        assert!(bodies[2].get("max_tokens").is_none());
+        assert!(bodies[2].get("stop").is_none());
+    }
+
+    #[pg_test]
+    fn test_generation_params_per_provider() {
+        let params = crate::params::GenerationParams {
+            temperature: Some(0.5),
+            max_tokens: Some(300),
+            top_p: Some(0.9),
+            presence_penalty: Some(0.1),
+            frequency_penalty: None,
+            seed: Some(7),
+            stop: Some(vec!["END".into()]),
+        };
+        let request = crate::providers::SummaryRequest {
+            params: &params,
+            ..summary_request("Some long text.")
+        };
+
+        Spi::run("SET pg_summarizer.provider = 'anthropic'").unwrap();
+        let body = crate::providers::configured("")
+            .unwrap()
+            .request_body(&request);
+        assert_eq!(300, body["max_tokens"]);
+        assert_eq!(0.5, body["temperature"]);
+        assert_eq!(0.9, body["top_p"]);
+        assert_eq!(serde_json::json!(["END"]), body["stop_sequences"]);
+        assert!(body.get("seed").is_none());
+        assert!(body.get("presence_penalty").is_none());
+
+        Spi::run("SET pg_summarizer.provider = 'gemini'").unwrap();
+        let body = crate::providers::configured("")
+            .unwrap()
+            .request_body(&request);
+        let config = &body["generationConfig"];
+        assert_eq!(300, config["maxOutputTokens"]);
+        assert_eq!(0.9, config["topP"]);
+        assert_eq!(0.1, config["presencePenalty"]);
+        assert_eq!(7, config["seed"]);
+        assert_eq!(serde_json::json!(["END"]), config["stopSequences"]);
+        assert!(config.get("frequencyPenalty").is_none());
+
+        Spi::run("SET pg_summarizer.provider = 'ollama'").unwrap();
+        let body = crate::providers::configured("")
+            .unwrap()
+            .request_body(&request);
+        let options = &body["options"];
+        assert_eq!(300, options["num_predict"]);
+        assert_eq!(0.5, options["temperature"]);
+        assert_eq!(7, options["seed"]);
+        assert!(options.get("num_ctx").is_none());
+    }
+
+    #[pg_test(
+        error = "3 is outside the valid range for parameter \"pg_summarizer.temperature\" (-1 .. 2)"
+    )]
+    fn test_temperature_setting_rejects_out_of_range() {
+        Spi::run("SET pg_summarizer.temperature = '3'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_temperature_range_of_provider() {
+        let base_url = stand_in_server(|_| panic!("a temperature anthropic rejects was sent"));
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.provider = 'anthropic'").unwrap();
+        // Within the general range, but above what Anthropic accepts, whether
+        // given as an argument or as a setting.
+        assert_eq!(
+            "22023",
+            sqlstate_of("PERFORM summarize('Some long text.', temperature => 1.5)")
+        );
+        assert_eq!(
+            "22023",
+            sqlstate_of("SET LOCAL pg_summarizer.temperature = 1.5")
+        );
+    }
+
+    #[pg_test(error = "top_p must be between 0 and 1, not 1.5")]
+    fn test_top_p_argument_rejects_out_of_range() {
+        Spi::run("SELECT summarize('Some long text.', top_p => 1.5)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_summarize_model_and_prompt_arguments() {
+        let base_url = stand_in_server(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let summary = format!(
+                "{} / {}",
+                body["model"].as_str().unwrap(),
+                body["messages"][0]["content"].as_str().unwrap()
+            );
+            let choices = serde_json::json!({"choices": [{"message": {"content": summary}}]});
+            (200, choices.to_string())
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.model = 'gpt-4o'").unwrap();
+        Spi::run("SET pg_summarizer.prompt = 'Summarize.'").unwrap();
+
+        let (default, other_model, other_prompt) = Spi::get_three::<String, String, String>(
+            "SELECT summarize('Some long text.'), \
+            summarize('Some long text.', 'gpt-4o-mini'), \
+            summarize('Some long text.', prompt => 'Be brief.', max_tokens => 20)",
+        )
+        .unwrap();
+        assert_eq!(Some("gpt-4o / Summarize.".into()), default);
+        assert_eq!(Some("gpt-4o-mini / Summarize.".into()), other_model);
+        assert_eq!(Some("gpt-4o / Be brief.".into()), other_prompt);
+        // The settings are left as they were.
+        assert_eq!(
+            Ok(Some("gpt-4o".into())),
+            Spi::get_one::<String>("SELECT current_setting('pg_summarizer.model')")
+        );
+    }
+
+    #[pg_test]
+    fn test_summary_style() {
+        let base_url = stand_in_server(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let prompt = body["messages"][0]["content"].as_str().unwrap();
+            let choices = serde_json::json!({"choices": [{"message": {"content": prompt}}]});
+            (200, choices.to_string())
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.prompt = 'Summarize.'").unwrap();
+        Spi::run("SET pg_summarizer.tone = 'neutral'").unwrap();
+
+        let (configured, styled, overridden) = Spi::get_three::<String, String, String>(
+            "SELECT summarize('Some long text.'), \
+            summarize('Some long text.', length => '50 words', format => 'bullets', \
+                audience => 'executives'), \
+            summarize('Some long text.', length => '1 sentence', format => 'TL;DR', \
+                tone => 'friendly')",
+        )
+        .unwrap();
+        assert_eq!(
+            Some("Summarize.\n\nUse this tone: neutral.".into()),
+            configured
+        );
+        assert_eq!(
+            Some(
+                "Summarize.\n\nWrite the summary as a list of bullet points, one key point \
+                per line, each line starting with \"- \". Use at most 50 words. Write for this \
+                audience: executives. Use this tone: neutral."
+                    .into()
+            ),
+            styled
+        );
+        assert_eq!(
+            Some(
+                "Summarize.\n\nWrite a TL;DR: the gist of the text in one or two short \
+                sentences, without an introduction. Use a single sentence. Use this tone: \
+                friendly."
+                    .into()
+            ),
+            overridden
+        );
+
+        Spi::run("RESET pg_summarizer.tone").unwrap();
+        assert_eq!(
+            Ok(Some("Summarize.".into())),
+            Spi::get_one::<String>("SELECT summarize('Some long text.')")
+        );
+    }
+
+    #[pg_test]
+    fn test_output_language() {
+        use std::sync::{Arc, Mutex};
+
+        const ENGLISH: &str = "This article explains how to write a PostgreSQL extension in \
+            Rust with pgrx, how to call the OpenAI API from a function, and how to test it.";
+        const GERMAN: &str = "Der Artikel erklärt, wie man eine PostgreSQL-Erweiterung in Rust \
+            mit pgrx schreibt, wie man die OpenAI-API aus einer Funktion aufruft und wie man \
+            sie testet.";
+
+        // Every other answer is in English.
+        let prompts = Arc::new(Mutex::new(Vec::<String>::new()));
+        let seen = prompts.clone();
+        let base_url = stand_in_server(move |request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let mut prompts = seen.lock().unwrap();
+            prompts.push(body["messages"][0]["content"].as_str().unwrap().to_string());
+            let summary = if prompts.len() % 2 == 1 {
+                ENGLISH
+            } else {
+                GERMAN
+            };
+            let choices = serde_json::json!({"choices": [{"message": {"content": summary}}]});
+            (200, choices.to_string())
+        });
+        Spi::run(&format!("SET pg_summarizer.base_url = '{}'", base_url)).unwrap();
+        Spi::run("SET pg_summarizer.api_key = 'sk-test'").unwrap();
+        Spi::run("SET pg_summarizer.prompt = 'Summarize.'").unwrap();
+        Spi::run("SET pg_summarizer.cache = on").unwrap();
+        Spi::run("SET pg_summarizer.output_language = 'German'").unwrap();
+
+        // The English answer is retried, and the German one cached.
+        let summarize = |sql: &str| Spi::get_one::<String>(sql).unwrap().unwrap();
+        assert_eq!(GERMAN, summarize("SELECT summarize('Some long text.')"));
+        assert_eq!(GERMAN, summarize("SELECT summarize('Some long text.')"));
+        assert_eq!(
+            vec!["Summarize.\n\nWrite the summary in German."; 2],
+            *prompts.lock().unwrap()
+        );
+
+        // Only warned about.
+        Spi::run("SET pg_summarizer.language_check = 'warning'").unwrap();
+        assert_eq!(
+            ENGLISH,
+            summarize("SELECT summarize('Other text.', language => 'deu')")
+        );
+        assert_eq!(3, prompts.lock().unwrap().len());
+        // And not cached, so the next call asks again.
+        assert_eq!(
+            GERMAN,
+            summarize("SELECT summarize('Other text.', language => 'deu')")
+        );
+        assert_eq!(4, prompts.lock().unwrap().len());
+
+        // The language of an input too short to tell is not checked.
+        Spi::run("SET pg_summarizer.language_check = 'retry'").unwrap();
+        assert_eq!(
+            ENGLISH,
+            summarize("SELECT summarize('Yet another text.', language => 'source')")
+        );
+        assert_eq!(
+            "Summarize.\n\nWrite the summary in the language of the <text>.",
+            prompts.lock().unwrap()[4]
+        );
+    }
+
+    #[pg_test(
+        error = "language must be source, a language name such as German or an ISO 639-3 code such as deu, not \"Klingon\""
+    )]
+    fn test_language_argument_rejects_unknown() {
+        Spi::run("SELECT summarize('Some long text.', language => 'Klingon')").unwrap();
+    }
+
+    #[pg_test(
+        error = "format must be paragraph, bullets, tldr, headline or abstract, not \"list\""
+    )]
+    fn test_format_argument_rejects_unknown() {
+        Spi::run("SELECT summarize('Some long text.', format => 'list')").unwrap();
+    }
+
+    #[pg_test(error = "invalid value for parameter \"pg_summarizer.length\": \"50\"")]
+    fn test_length_setting_rejects_missing_unit() {
+        Spi::run("SET pg_summarizer.length = '50'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_summarize_profiles() {
+        let base_url = stand_in_server(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let summary = format!(
+                "{} / {} / {} / {}",
+                request.header("authorization"),
+                body["model"].as_str().unwrap(),
+                body["messages"][0]["content"].as_str().unwrap(),
+                body["max_tokens"]
+            );
+            let choices = serde_json::json!({"choices": [{"message": {"content": summary}}]});
+            (200, choices.to_string())
+        });
+        Spi::run_with_args(
+            "INSERT INTO summarize_profiles \
+            (name, provider, base_url, model, prompt, parameters, api_key_setting) \
+            VALUES ('legal_short', 'openai', $1, 'gpt-4o-mini', 'Legal summary.', \
+            '{\"temperature\": 0, \"max_tokens\": 100}', 'pg_summarize_test.legal_key')",
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                base_url.as_str().into_datum(),
+            )]),
+        )
+        .unwrap();
+        Spi::run("SET pg_summarize_test.legal_key = 'sk-legal'").unwrap();
+        Spi::run("SET pg_summarizer.model = 'gpt-4o'").unwrap();
+
+        assert_eq!(
+            Ok(Some(
+                "Bearer sk-legal / gpt-4o-mini / Legal summary. / 100".to_string()
+            )),
+            Spi::get_one::<String>("SELECT summarize('Some long text.', profile => 'legal_short')")
+        );
+        assert_eq!(
+            Ok(Some(
+                "Bearer sk-legal / gpt-4o / Legal summary. / 20".to_string()
+            )),
+            Spi::get_one::<String>(
+                "SELECT summarize('Some long text.', profile => 'legal_short', \
+                model => 'gpt-4o', max_tokens => 20)"
+            )
+        );
+        Spi::run("SET pg_summarizer.default_profile = 'legal_short'").unwrap();
+        assert_eq!(
+            Ok(Some(
+                "Bearer sk-legal / gpt-4o-mini / Legal summary. / 100".to_string()
+            )),
+            Spi::get_one::<String>("SELECT summarize('Some long text.')")
+        );
+
+        Spi::run("UPDATE summarize_profiles SET parameters = '{\"temprature\": 0}'").unwrap();
+        assert_eq!("22023", sqlstate_of("PERFORM 1"));
+        Spi::run("RESET pg_summarize_test.legal_key").unwrap();
+        Spi::run("UPDATE summarize_profiles SET parameters = '{}'").unwrap();
+        assert_eq!("28000", sqlstate_of("PERFORM 1"));
+        assert_eq!(
+            "22023",
+            sqlstate_of("SET LOCAL pg_summarizer.default_profile = 'legal_long'")
+        );
+    }
+
+    /// Runs `setup` and then `summarize()` in a PL/pgSQL block and returns the
+    /// SQLSTATE it raised.
+    fn sqlstate_of(setup: &str) -> String {
+        Spi::run(&format!(
+            "DO $$ BEGIN {}; PERFORM summarize('Some long text.'); \
+            EXCEPTION WHEN OTHERS THEN \
+            PERFORM set_config('pg_summarize_test.sqlstate', SQLSTATE, false); END $$",
+            setup
+        ))
+        .unwrap();
+        Spi::get_one::<String>("SELECT current_setting('pg_summarize_test.sqlstate')")
+            .unwrap()
+            .unwrap()
+    }
+
+    fn summary_request(input: &str) -> crate::providers::SummaryRequest<'_> {
+        static PARAMS: crate::params::GenerationParams = crate::params::GenerationParams {
+            temperature: None,
+            max_tokens: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            stop: None,
+        };
+        crate::providers::SummaryRequest {
+            input,
+            model: "test-model",
+            prompt: "Be brief.",
+            params: &PARAMS,
+        }
+    }
+
+    pub struct StandInRequest {
+        /// Sequence number of the TCP connection the request arrived on.
+        pub connection: usize,
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl StandInRequest {
+        pub fn header(&self, name: &str) -> &str {
+            self.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map_or("", |(_, value)| value)
+        }
+    }
+
+    /// Starts a minimal HTTP server on a free local port, standing in for a
+    /// provider API, and returns its base URL. `respond` maps each request to
+    /// a status code and JSON body.
+    pub fn stand_in_server(
+        respond: impl Fn(&StandInRequest) -> (u16, String) + Send + Sync + 'static,
+    ) -> String {
+        stand_in_server_with_headers(move |request| {
+            let (status, body) = respond(request);
+            (status, Vec::new(), body)
+        })
+    }
+
+    /// Like [`stand_in_server`], but `respond` also returns extra response
+    /// headers. Connections are kept alive until the client closes them.
+    pub fn stand_in_server_with_headers(
+        respond: impl Fn(&StandInRequest) -> (u16, Vec<(&'static str, String)>, String)
+            + Send
+            + Sync
+            + 'static,
+    ) -> String {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let respond = Arc::new(respond);
+        std::thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let Ok(mut stream) = stream else { continue };
+                let respond = respond.clone();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut request_line = String::new();
+                        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut parts = request_line.split_whitespace();
+                        let method = parts.next().unwrap_or_default().to_string();
+                        let path = parts.next().unwrap_or_default().to_string();
+
+                        let mut headers = Vec::new();
+                        loop {
+                            let mut line = String::new();
+                            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                                return;
+                            }
+                            match line.trim_end().split_once(':') {
+                                Some((name, value)) => headers
+                                    .push((name.trim().to_lowercase(), value.trim().to_string())),
+                                None => break,
+                            }
+                        }
+                        let mut request = StandInRequest {
+                            connection,
+                            method,
+                            path,
+                            headers,
+                            body: Vec::new(),
+                        };
+                        let length = request.header("content-length").parse().unwrap_or(0);
+                        request.body.resize(length, 0);
+                        if reader.read_exact(&mut request.body).is_err() {
+                            return;
+                        }
+
+                        let (status, headers, body) = respond(&request);
+                        let headers: String = headers
+                            .iter()
+                            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                            .collect();
+                        let response = format!(
+                            "HTTP/1.1 {} Stand-in\r\nContent-Type: application/json\r\n{}\
+                            Content-Length: {}\r\n\r\n{}",
+                            status,
+                            headers,
+                            body.len(),
+                            body
+                        );
+                        if stream.write_all(response.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        base_url
+    }
 }
 
 /// This module is required by `cargo pgrx test` invocations.
@@ -107,6 +2415,6 @@ pub mod pg_test {
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        vec!["shared_preload_libraries = 'pg_summarize'"]
     }
 }
@@ -0,0 +1,190 @@
+//! Summarizing inputs longer than the model's context window, or than
+//! `pg_summarizer.max_input_tokens`.
+//!
+//! Such inputs are split into chunks that fit, overlapping by
+//! `pg_summarizer.chunk_overlap` tokens. With `map_reduce` every chunk is
+//! summarized on its own, concurrently, and the joined summaries are
+//! summarized in turn, split again for as long as they do not fit. With
+//! `refine` the chunks are read in order, each request carrying the summary
+//! so far along with the next chunk, which is slower but keeps the thread of
+//! the text.
+
+use crate::error::{ErrorKind, SummarizeError};
+use crate::{guc, Summarizer};
+use pgrx::PostgresGucEnum;
+
+/// Values accepted by `pg_summarizer.long_text_strategy`.
+#[allow(non_camel_case_types)]
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Debug)]
+pub enum LongTextStrategy {
+    error,
+    truncate,
+    map_reduce,
+    refine,
+}
+
+const PART_INSTRUCTION: &str = "The <text> is one part of a longer document.";
+
+const COMBINE_INSTRUCTION: &str = "The <text> consists of summaries of consecutive \
+    parts of a longer document. Combine them into a single summary of the whole document.";
+
+const REFINE_INSTRUCTION: &str = "The <text> starts with a summary of the earlier \
+    parts of a longer document, inside <summary> tags, followed by the next part of the \
+    document. Write a summary of the document so far, including the new part.";
+
+/// Room left in the context window for the answer when
+/// `pg_summarizer.max_tokens` does not bound it.
+const ANSWER_TOKENS: usize = 1024;
+
+/// Fewest tokens the context window must leave for a chunk of a long input.
+/// With less room, splitting would take a request for every few words, so
+/// the input is rejected.
+const MIN_CHUNK_TOKENS: usize = 256;
+
+/// Room left in the context window for the instructions added to the prompt
+/// for parts of a long input and the framing of the messages.
+const FRAMING_TOKENS: usize = 128;
+
+impl Summarizer {
+    /// Tokens an input may have before `pg_summarizer.long_text_strategy`
+    /// applies: what the model's context window leaves after the prompt and
+    /// the answer, or `pg_summarizer.max_input_tokens` if that is lower.
+    /// `None` if neither is known.
+    pub(crate) fn input_limit(&self) -> Option<usize> {
+        let max_tokens = Some(guc::MAX_INPUT_TOKENS.get() as usize).filter(|&max| max > 0);
+        self.window_budget().into_iter().chain(max_tokens).min()
+    }
+
+    /// What the model's context window leaves for the input after the prompt
+    /// and the answer, if the window is known; zero if they fill it.
+    fn window_budget(&self) -> Option<usize> {
+        let answer = self
+            .params
+            .max_tokens
+            .map_or(ANSWER_TOKENS, |max_tokens| max_tokens as usize);
+        self.provider.context_window(&self.model).map(|window| {
+            window.saturating_sub(
+                self.tokenizer.count(&self.style.apply(&self.prompt)) + answer + FRAMING_TOKENS,
+            )
+        })
+    }
+
+    pub(crate) fn too_long(&self, tokens: usize, max_tokens: usize) -> SummarizeError {
+        SummarizeError::new(
+            ErrorKind::InputTooLong,
+            format!(
+                "input is too long: {} tokens, at most {} fit with model \"{}\"",
+                tokens, max_tokens, self.model
+            ),
+        )
+    }
+
+    /// Whether the context window leaves room to split long inputs into
+    /// chunks without making a request for every few words of them.
+    pub(crate) fn can_split(&self) -> bool {
+        self.window_budget()
+            .is_none_or(|budget| budget >= MIN_CHUNK_TOKENS)
+    }
+
+    /// `pg_summarizer.chunk_overlap`, but at most half of `chunk_tokens`, so
+    /// that each chunk moves well past the one before.
+    fn chunk_overlap(&self, chunk_tokens: usize) -> usize {
+        (guc::CHUNK_OVERLAP.get() as usize).min(chunk_tokens / 2)
+    }
+
+    fn with_instruction(&self, instruction: &str) -> String {
+        format!("{}\n\n{}", self.prompt, instruction)
+    }
+
+    /// Summaries of parts are kept in full; only the request that makes the
+    /// final summary is given the style.
+    fn final_prompt(&self, instruction: &str) -> String {
+        self.style.apply(&self.with_instruction(instruction))
+    }
+
+    /// Summarizes the chunks of `input` concurrently, then their summaries,
+    /// until the summaries fit in one request.
+    pub(crate) fn map_reduce(
+        &self,
+        input: &str,
+        max_tokens: usize,
+        parallelism: usize,
+    ) -> Result<String, SummarizeError> {
+        let overlap = self.chunk_overlap(max_tokens);
+        let part_prompt = self.with_instruction(PART_INSTRUCTION);
+        let combine_prompt = self.with_instruction(COMBINE_INSTRUCTION);
+
+        let mut summaries = self
+            .request_all(
+                &part_prompt,
+                &self.tokenizer.split(input, max_tokens, overlap),
+                parallelism,
+            )
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?
+            .join("\n\n");
+        let mut length = self.tokenizer.count(input);
+        while self.tokenizer.count(&summaries) > max_tokens {
+            // A round that does not shrink the text would never end.
+            if self.tokenizer.count(&summaries) >= length {
+                return Err(SummarizeError::new(
+                    ErrorKind::InputTooLong,
+                    "summaries of the parts of the input are not shorter than the parts",
+                ));
+            }
+            length = self.tokenizer.count(&summaries);
+            summaries = self
+                .request_all(
+                    &combine_prompt,
+                    &self.tokenizer.split(&summaries, max_tokens, 0),
+                    parallelism,
+                )
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?
+                .join("\n\n");
+        }
+        self.request_summaries(&self.final_prompt(COMBINE_INSTRUCTION), &[&summaries], 1)
+            .pop()
+            .expect("one result per input")
+    }
+
+    /// Summarizes the first chunk of `input`, then updates the summary with
+    /// each following chunk in turn.
+    pub(crate) fn refine(&self, input: &str, max_tokens: usize) -> Result<String, SummarizeError> {
+        let end = self.tokenizer.prefix_len(input, max_tokens);
+        let mut chunk_tokens = max_tokens;
+        let mut summary = self
+            .request_all(
+                &self.with_instruction(PART_INSTRUCTION),
+                &[&input[..end]],
+                1,
+            )
+            .pop()
+            .expect("one result per input")?;
+        let mut start = 0;
+        let mut end = end;
+        while end < input.len() {
+            start += self.tokenizer.next_start(
+                &input[start..],
+                end - start,
+                self.chunk_overlap(chunk_tokens),
+            );
+            let head = format!("<summary>{}</summary>\n\n", summary);
+            // The summary takes up part of the request, but at least a
+            // quarter is left for the text.
+            let budget = max_tokens
+                .saturating_sub(self.tokenizer.count(&head))
+                .max(max_tokens / 4);
+            chunk_tokens = budget;
+            end = start + self.tokenizer.prefix_len(&input[start..], budget);
+            let text = format!("{}{}", head, &input[start..end]);
+            let mut results = if end == input.len() {
+                self.request_summaries(&self.final_prompt(REFINE_INSTRUCTION), &[&text], 1)
+            } else {
+                self.request_all(&self.with_instruction(REFINE_INSTRUCTION), &[&text], 1)
+            };
+            summary = results.pop().expect("one result per input")?;
+        }
+        Ok(summary)
+    }
+}
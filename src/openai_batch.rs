@@ -0,0 +1,540 @@
+//! Backfills through the OpenAI Batch API, at half the price of regular
+//! requests in exchange for results within 24 hours.
+//!
+//! `summarize_openai_batch_submit()` turns the rows of a query into a JSONL
+//! file of chat completion requests, with the same bodies `summarize()` would
+//! send, uploads it and starts a batch. Batches are tracked in
+//! `summarize_openai_batches` and polled by the first background worker every
+//! `pg_summarizer.openai_batch_poll_interval`, or by calling
+//! `summarize_openai_batch_poll()`. Once a batch ends, its summaries are
+//! written to the target table, matching each request's `custom_id` against
+//! the key column.
+
+use crate::error::{ErrorKind, SummarizeError};
+use crate::profiles::Profile;
+use crate::providers::{OpenAi, Provider, ProviderKind};
+use crate::retry::RetryPolicy;
+use crate::{http, registrations, Summarizer};
+use pgrx::prelude::*;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+
+/// The endpoint every request in a batch is made to.
+const ENDPOINT: &str = "/v1/chat/completions";
+
+/// Batch statuses after which nothing more happens to a batch.
+const FINAL_STATUSES: [&str; 4] = ["completed", "failed", "expired", "cancelled"];
+
+/// The most requests the Batch API takes in one input file.
+const MAX_BATCH_REQUESTS: usize = 50_000;
+
+/// The largest input file the Batch API takes, in bytes.
+const MAX_BATCH_BYTES: usize = 200_000_000;
+
+/// Summaries written back per statement.
+const WRITE_CHUNK: usize = 10_000;
+
+extension_sql!(
+    r#"
+CREATE TABLE summarize_openai_batches (
+    id bigserial PRIMARY KEY,
+    batch_id text NOT NULL UNIQUE,
+    input_file_id text NOT NULL,
+    target_relation regclass NOT NULL,
+    key_column text NOT NULL,
+    summary_column text NOT NULL,
+    status text NOT NULL,
+    request_count bigint NOT NULL,
+    completed_count bigint NOT NULL DEFAULT 0,
+    failed_count bigint NOT NULL DEFAULT 0,
+    written_count bigint NOT NULL DEFAULT 0,
+    error text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    finished_at timestamptz
+);
+COMMENT ON TABLE summarize_openai_batches IS 'Batches started with summarize_openai_batch_submit()';
+
+-- Writes summaries of batch batch_id to its target table; returns the number
+-- of rows updated, or the error message if that failed, e.g. because the
+-- table was dropped.
+CREATE FUNCTION summarize_openai_batch_write_back(
+    batch_id bigint, keys text[], summaries text[], OUT written bigint, OUT error text)
+LANGUAGE plpgsql AS $$
+DECLARE
+    batch summarize_openai_batches;
+BEGIN
+    SELECT * INTO batch FROM summarize_openai_batches WHERE id = batch_id;
+    EXECUTE format(
+        'WITH updated AS (UPDATE %1$s AS t SET %2$I = r.summary '
+        'FROM unnest($1::text[], $2::text[]) AS r(key, summary) '
+        'WHERE t.%3$I = r.key::%4$s RETURNING 1) SELECT count(*) FROM updated',
+        batch.target_relation,
+        batch.summary_column,
+        batch.key_column,
+        -- Compared in the key column's own type, so its index can be used.
+        (SELECT format_type(atttypid, atttypmod) FROM pg_attribute
+            WHERE attrelid = batch.target_relation AND attname = batch.key_column
+                AND NOT attisdropped))
+    INTO written USING keys, summaries;
+EXCEPTION WHEN OTHERS THEN
+    written := 0;
+    error := SQLERRM;
+END
+$$;
+"#,
+    name = "summarize_openai_batches",
+);
+
+/// Summarizes the rows of `query`, whose first two columns are the key and
+/// the text, through the Batch API. The summaries are written to
+/// `summary_column` of `target`, in the row whose `key_column` equals the key.
+/// Rows beyond what one batch takes are split over several batches. Returns
+/// the ids of the batches in `summarize_openai_batches`.
+#[pg_extern]
+fn summarize_openai_batch_submit(
+    query: &str,
+    target: &str,
+    key_column: default!(&str, "'id'"),
+    summary_column: default!(&str, "'summary'"),
+) -> SetOfIterator<'static, i64> {
+    for column in [key_column, summary_column] {
+        registrations::require_column(target, column);
+    }
+    let api = BatchApi::configured().unwrap_or_else(|e| e.report());
+    let summarizer = Summarizer::configured(None, None).unwrap_or_else(|e| e.report());
+
+    let rows = Spi::connect(|client| {
+        client
+            .select(
+                &format!(
+                    "SELECT custom_id::text, input::text FROM ({}) AS q(custom_id, input)",
+                    query
+                ),
+                None,
+                None,
+            )?
+            .map(|row| Ok((row.get::<String>(1)?, row.get::<String>(2)?)))
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .unwrap_or_else(|e| error!("could not run batch query: {}", e));
+
+    let prompt = summarizer.style.apply(&summarizer.prompt);
+    let mut requests = Vec::new();
+    for (custom_id, input) in &rows {
+        let (Some(custom_id), Some(input)) = (custom_id, input) else {
+            continue;
+        };
+        let line = json!({
+            "custom_id": custom_id,
+            "method": "POST",
+            "url": ENDPOINT,
+            "body": summarizer.provider.request_body(&summarizer.request(input, &prompt)),
+        });
+        requests.push(line.to_string());
+    }
+    if requests.is_empty() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_NO_DATA_FOUND,
+            "batch query returned no rows with a key and a text"
+        );
+    }
+
+    // All batches are started before any is recorded, as an error rolls back
+    // the rows; if one cannot be started, those already running are cancelled.
+    let mut started = Vec::new();
+    for requests in split_requests(&requests, MAX_BATCH_REQUESTS, MAX_BATCH_BYTES) {
+        match api.start(requests.join("\n").into_bytes()) {
+            Ok((input_file_id, batch)) => started.push((input_file_id, batch, requests.len())),
+            Err(e) => {
+                for (input_file_id, batch, _) in &started {
+                    api.abandon(&str_field(batch, "id"), input_file_id);
+                }
+                e.report()
+            }
+        }
+    }
+    let ids: Vec<i64> = started
+        .into_iter()
+        .map(|(input_file_id, batch, request_count)| {
+            Spi::get_one_with_args::<i64>(
+                "INSERT INTO summarize_openai_batches (batch_id, input_file_id, target_relation, \
+                key_column, summary_column, status, request_count) \
+                VALUES ($1, $2, $3::regclass, $4, $5, $6, $7) RETURNING id",
+                vec![
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        str_field(&batch, "id").into_datum(),
+                    ),
+                    (PgBuiltInOids::TEXTOID.oid(), input_file_id.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), target.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), key_column.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), summary_column.into_datum()),
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        str_field(&batch, "status").into_datum(),
+                    ),
+                    (
+                        PgBuiltInOids::INT8OID.oid(),
+                        (request_count as i64).into_datum(),
+                    ),
+                ],
+            )
+            .unwrap_or_else(|e| error!("could not record batch: {}", e))
+            .unwrap_or_default()
+        })
+        .collect();
+    SetOfIterator::new(ids)
+}
+
+/// Splits the lines of a request file into files of at most `max_requests`
+/// lines and `max_bytes` bytes each. A line longer than `max_bytes` gets a
+/// file of its own, for the API to reject.
+pub(crate) fn split_requests(
+    requests: &[String],
+    max_requests: usize,
+    max_bytes: usize,
+) -> Vec<&[String]> {
+    let mut files = Vec::new();
+    let (mut start, mut bytes) = (0, 0);
+    for (i, request) in requests.iter().enumerate() {
+        // Lines are joined with a newline.
+        let size = request.len() + 1;
+        if i > start && (i - start == max_requests || bytes + size > max_bytes) {
+            files.push(&requests[start..i]);
+            (start, bytes) = (i, 0);
+        }
+        bytes += size;
+    }
+    if start < requests.len() {
+        files.push(&requests[start..]);
+    }
+    files
+}
+
+/// Checks on every unfinished batch, and writes back the summaries of those
+/// that ended. Returns the number of batches that ended.
+#[pg_extern]
+fn summarize_openai_batch_poll() -> i64 {
+    poll()
+}
+
+/// Asks OpenAI to cancel batch `id`. Summaries it already made are still
+/// written back when the cancellation completes.
+#[pg_extern]
+fn summarize_openai_batch_cancel(id: i64) {
+    let batch_id = Spi::get_one_with_args::<String>(
+        "SELECT batch_id FROM summarize_openai_batches WHERE id = $1 AND finished_at IS NULL",
+        vec![(PgBuiltInOids::INT8OID.oid(), id.into_datum())],
+    )
+    .unwrap_or_else(|e| error!("could not read batch: {}", e));
+    let Some(batch_id) = batch_id else {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_NO_DATA_FOUND,
+            format!("batch {} does not exist or has already ended", id)
+        );
+    };
+    let api = BatchApi::configured().unwrap_or_else(|e| e.report());
+    api.send(|client, api| client.post(api.url(&format!("/batches/{}/cancel", batch_id))))
+        .and_then(|body| Ok(serde_json::from_str::<Value>(&body)?))
+        .and_then(|batch| update_status(id, &batch))
+        .unwrap_or_else(|e| e.report());
+}
+
+/// Polls the unfinished batches; see `summarize_openai_batch_poll()`. Runs in
+/// the first background worker, so nothing here raises an error: a batch that
+/// cannot be checked right now is skipped with a warning, and the failure is
+/// recorded in its row.
+pub fn poll() -> i64 {
+    let batches = Spi::connect(|mut client| {
+        client
+            .update(
+                "SELECT id, batch_id FROM summarize_openai_batches WHERE finished_at IS NULL \
+                ORDER BY id FOR UPDATE SKIP LOCKED",
+                None,
+                None,
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<i64>(1)?.unwrap_or_default(),
+                    row.get::<String>(2)?.unwrap_or_default(),
+                ))
+            })
+            .collect::<Result<Vec<_>, pgrx::spi::Error>>()
+    });
+    let batches = match batches {
+        Ok(batches) if !batches.is_empty() => batches,
+        Ok(_) => return 0,
+        Err(e) => {
+            warning!("could not read summarize_openai_batches: {}", e);
+            return 0;
+        }
+    };
+
+    let api = match BatchApi::configured() {
+        Ok(api) => api,
+        Err(e) => {
+            e.warn();
+            return 0;
+        }
+    };
+    let mut ended = 0;
+    for (id, batch_id) in batches {
+        match poll_batch(&api, id, &batch_id) {
+            Ok(true) => ended += 1,
+            Ok(false) => {}
+            Err(e) => {
+                if let Err(e) = record_error(id, &e.to_string()) {
+                    e.warn();
+                }
+                e.warn();
+            }
+        }
+    }
+    ended
+}
+
+/// Updates batch `id` from the API; once it has ended, writes back its
+/// summaries and returns true. A batch whose summaries cannot be written
+/// still ends, with the reason in its `error`.
+fn poll_batch(api: &BatchApi, id: i64, batch_id: &str) -> Result<bool, SummarizeError> {
+    let batch: Value = serde_json::from_str(
+        &api.send(|client, api| client.get(api.url(&format!("/batches/{}", batch_id))))?,
+    )?;
+    update_status(id, &batch)?;
+    if !FINAL_STATUSES.contains(&str_field(&batch, "status").as_str()) {
+        return Ok(false);
+    }
+
+    let (written, error) = match batch["output_file_id"].as_str() {
+        Some(file_id) => write_back(
+            id,
+            &api.send(|client, api| client.get(api.url(&format!("/files/{}/content", file_id))))?,
+        )?,
+        None => (0, None),
+    };
+    let error = error.or_else(|| {
+        batch["errors"]["data"][0]["message"]
+            .as_str()
+            .map(str::to_string)
+    });
+    Spi::run_with_args(
+        "UPDATE summarize_openai_batches SET written_count = $2, error = $3, \
+        finished_at = now() WHERE id = $1",
+        Some(vec![
+            (PgBuiltInOids::INT8OID.oid(), id.into_datum()),
+            (PgBuiltInOids::INT8OID.oid(), written.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), error.into_datum()),
+        ]),
+    )
+    .map_err(|e| record_failed(id, e))?;
+    Ok(true)
+}
+
+/// Keeps the reason batch `id` could not be checked, until a later poll
+/// succeeds.
+fn record_error(id: i64, error: &str) -> Result<(), SummarizeError> {
+    Spi::run_with_args(
+        "UPDATE summarize_openai_batches SET error = $2 WHERE id = $1",
+        Some(vec![
+            (PgBuiltInOids::INT8OID.oid(), id.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), error.into_datum()),
+        ]),
+    )
+    .map_err(|e| record_failed(id, e))
+}
+
+fn record_failed(id: i64, e: pgrx::spi::Error) -> SummarizeError {
+    SummarizeError::new(
+        ErrorKind::InvalidConfiguration,
+        format!("could not record batch {}: {}", id, e),
+    )
+}
+
+fn update_status(id: i64, batch: &Value) -> Result<(), SummarizeError> {
+    let count = |name: &str| batch["request_counts"][name].as_i64().unwrap_or_default();
+    Spi::run_with_args(
+        "UPDATE summarize_openai_batches \
+        SET status = $2, completed_count = $3, failed_count = $4, error = NULL WHERE id = $1",
+        Some(vec![
+            (PgBuiltInOids::INT8OID.oid(), id.into_datum()),
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                str_field(batch, "status").into_datum(),
+            ),
+            (
+                PgBuiltInOids::INT8OID.oid(),
+                count("completed").into_datum(),
+            ),
+            (PgBuiltInOids::INT8OID.oid(), count("failed").into_datum()),
+        ]),
+    )
+    .map_err(|e| record_failed(id, e))
+}
+
+/// Writes the summaries in the output file of batch `id` to its target table
+/// and returns how many rows were updated, and the error that stopped it if
+/// any. Failed requests are skipped; the batch's `failed_count` tells how many
+/// there were.
+fn write_back(id: i64, output: &str) -> Result<(i64, Option<String>), SummarizeError> {
+    let provider = OpenAi::new(None, "");
+    let (mut keys, mut summaries) = (Vec::new(), Vec::new());
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(result) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        let response = &result["response"];
+        if response["status_code"] != 200 {
+            continue;
+        }
+        if let (Some(key), Ok(summary)) = (
+            result["custom_id"].as_str(),
+            provider.parse_response(&response["body"].to_string()),
+        ) {
+            keys.push(key.to_string());
+            summaries.push(summary);
+        }
+    }
+
+    let mut written = 0;
+    for (keys, summaries) in keys.chunks(WRITE_CHUNK).zip(summaries.chunks(WRITE_CHUNK)) {
+        let (count, error) = Spi::get_two_with_args::<i64, String>(
+            "SELECT * FROM summarize_openai_batch_write_back($1, $2, $3)",
+            vec![
+                (PgBuiltInOids::INT8OID.oid(), id.into_datum()),
+                (
+                    PgBuiltInOids::TEXTARRAYOID.oid(),
+                    keys.to_vec().into_datum(),
+                ),
+                (
+                    PgBuiltInOids::TEXTARRAYOID.oid(),
+                    summaries.to_vec().into_datum(),
+                ),
+            ],
+        )
+        .map_err(|e| record_failed(id, e))?;
+        written += count.unwrap_or_default();
+        if error.is_some() {
+            return Ok((written, error));
+        }
+    }
+    Ok((written, None))
+}
+
+fn str_field(value: &Value, name: &str) -> String {
+    value[name].as_str().unwrap_or_default().to_string()
+}
+
+/// The files and batches endpoints of the configured OpenAI API.
+struct BatchApi {
+    openai: OpenAi,
+    client: Client,
+    policy: RetryPolicy,
+}
+
+impl BatchApi {
+    fn configured() -> Result<Self, SummarizeError> {
+        let profile = Profile::configured(None)?;
+        if profile.provider != ProviderKind::openai {
+            return Err(SummarizeError::new(
+                ErrorKind::InvalidConfiguration,
+                "the Batch API is only available with the openai provider",
+            )
+            .with_hint("SET pg_summarizer.provider = 'openai'."));
+        }
+        let Some(api_key) = profile.api_key else {
+            return Err(SummarizeError::new(
+                ErrorKind::MissingCredentials,
+                format!("{} is not set", profile.api_key_setting),
+            ));
+        };
+        Ok(BatchApi {
+            openai: OpenAi::new(profile.base_url, &api_key),
+            client: http::client()?,
+            policy: RetryPolicy::configured(),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.openai.url(path)
+    }
+
+    /// Sends the request built by `build`, with retries, and returns the
+    /// response body.
+    fn send(
+        &self,
+        build: impl Fn(&Client, &Self) -> RequestBuilder,
+    ) -> Result<String, SummarizeError> {
+        let build = &build;
+        http::block_on(self.policy.run(move || async move {
+            let response = build(&self.client, self)
+                .headers(self.openai.auth_headers()?)
+                .send()
+                .await?;
+            crate::response_body(response).await
+        }))
+    }
+
+    /// Uploads a JSONL file of requests and returns its file id.
+    fn upload(&self, jsonl: Vec<u8>) -> Result<String, SummarizeError> {
+        let body = self.send(|client, api| {
+            let file = Part::bytes(jsonl.clone())
+                .file_name("pg_summarize.jsonl")
+                .mime_str("application/jsonl")
+                .expect("valid MIME type");
+            client
+                .post(api.url("/files"))
+                .multipart(Form::new().text("purpose", "batch").part("file", file))
+        })?;
+        let file: Value = serde_json::from_str(&body)?;
+        Ok(str_field(&file, "id"))
+    }
+
+    /// Uploads a JSONL file of requests and starts a batch over it. Returns
+    /// the file id and the batch object. The file is deleted again if the
+    /// batch cannot be started.
+    fn start(&self, jsonl: Vec<u8>) -> Result<(String, Value), SummarizeError> {
+        let file_id = self.upload(jsonl)?;
+        match self.create(&file_id) {
+            Ok(batch) => Ok((file_id, batch)),
+            Err(e) => {
+                self.delete_file(&file_id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Cancels a batch that will not be recorded, and deletes its file.
+    /// Failures are only warned about, as the error that led here is raised.
+    fn abandon(&self, batch_id: &str, input_file_id: &str) {
+        if let Err(e) =
+            self.send(|client, api| client.post(api.url(&format!("/batches/{}/cancel", batch_id))))
+        {
+            e.warn();
+        }
+        self.delete_file(input_file_id);
+    }
+
+    /// Deletes an uploaded file, warning if that fails.
+    fn delete_file(&self, file_id: &str) {
+        if let Err(e) =
+            self.send(|client, api| client.delete(api.url(&format!("/files/{}", file_id))))
+        {
+            e.warn();
+        }
+    }
+
+    /// Starts a batch over an uploaded file and returns the batch object.
+    fn create(&self, input_file_id: &str) -> Result<Value, SummarizeError> {
+        let body = self.send(|client, api| {
+            client.post(api.url("/batches")).json(&json!({
+                "input_file_id": input_file_id,
+                "endpoint": ENDPOINT,
+                "completion_window": "24h",
+            }))
+        })?;
+        Ok(serde_json::from_str(&body)?)
+    }
+}
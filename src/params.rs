@@ -0,0 +1,150 @@
+//! Generation parameters: how long, how random and how repeatable a summary
+//! is.
+//!
+//! Each comes from its `pg_summarizer.*` setting unless given as an argument
+//! of `summarize()`. Providers send the ones they have an equivalent for,
+//! under their own names, and leave the others out.
+
+use crate::guc;
+use serde_json::{Map, Value};
+use std::ops::RangeInclusive;
+
+pub const TEMPERATURE_RANGE: RangeInclusive<f64> = 0.0..=2.0;
+/// Temperatures Anthropic models take, also through Bedrock.
+pub const ANTHROPIC_TEMPERATURE_RANGE: RangeInclusive<f64> = 0.0..=1.0;
+pub const TOP_P_RANGE: RangeInclusive<f64> = 0.0..=1.0;
+pub const PENALTY_RANGE: RangeInclusive<f64> = -2.0..=2.0;
+
+/// Parameters of a summarization request; `None` leaves the provider's
+/// default in place.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GenerationParams {
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<i32>,
+    pub top_p: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub seed: Option<i64>,
+    pub stop: Option<Vec<String>>,
+}
+
+impl GenerationParams {
+    /// The parameters set with `pg_summarizer.*` settings. Values below the
+    /// valid range, such as the defaults, leave a parameter unset.
+    pub fn configured() -> Self {
+        GenerationParams {
+            temperature: Some(guc::TEMPERATURE.get()).filter(|t| TEMPERATURE_RANGE.contains(t)),
+            max_tokens: Some(guc::MAX_TOKENS.get()).filter(|&max| max > 0),
+            top_p: Some(guc::TOP_P.get()).filter(|p| TOP_P_RANGE.contains(p)),
+            presence_penalty: Some(guc::PRESENCE_PENALTY.get())
+                .filter(|p| PENALTY_RANGE.contains(p)),
+            frequency_penalty: Some(guc::FREQUENCY_PENALTY.get())
+                .filter(|p| PENALTY_RANGE.contains(p)),
+            seed: Some(guc::SEED.get())
+                .filter(|&seed| seed >= 0)
+                .map(i64::from),
+            stop: guc::STOP.get().and_then(|v| parse_stop(&v).ok()),
+        }
+    }
+
+    /// The parameters of a profile, a JSON object with the same keys as the
+    /// arguments of `summarize()`.
+    pub fn from_json(value: Value) -> Result<Self, String> {
+        let Value::Object(fields) = value else {
+            return Err("parameters must be a JSON object".into());
+        };
+        let mut params = GenerationParams::default();
+        for (name, value) in fields {
+            let invalid = || format!("invalid value for {}: {}", name, value);
+            match name.as_str() {
+                "temperature" => params.temperature = Some(value.as_f64().ok_or_else(invalid)?),
+                "max_tokens" => {
+                    params.max_tokens = Some(
+                        value
+                            .as_i64()
+                            .and_then(|max| i32::try_from(max).ok())
+                            .ok_or_else(invalid)?,
+                    )
+                }
+                "top_p" => params.top_p = Some(value.as_f64().ok_or_else(invalid)?),
+                "presence_penalty" => {
+                    params.presence_penalty = Some(value.as_f64().ok_or_else(invalid)?)
+                }
+                "frequency_penalty" => {
+                    params.frequency_penalty = Some(value.as_f64().ok_or_else(invalid)?)
+                }
+                "seed" => params.seed = Some(value.as_i64().ok_or_else(invalid)?),
+                "stop" => params.stop = Some(parse_stop(&value.to_string())?),
+                _ => return Err(format!("unknown parameter \"{}\"", name)),
+            }
+        }
+        params.validate()?;
+        Ok(params)
+    }
+
+    /// These parameters, with the ones not given taken from `fallback`.
+    pub fn or(self, fallback: GenerationParams) -> Self {
+        GenerationParams {
+            temperature: self.temperature.or(fallback.temperature),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            top_p: self.top_p.or(fallback.top_p),
+            presence_penalty: self.presence_penalty.or(fallback.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            seed: self.seed.or(fallback.seed),
+            stop: self.stop.or(fallback.stop),
+        }
+    }
+
+    /// Checks values given as arguments, which no check hook has seen.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value, range) in [
+            ("temperature", self.temperature, TEMPERATURE_RANGE),
+            ("top_p", self.top_p, TOP_P_RANGE),
+            ("presence_penalty", self.presence_penalty, PENALTY_RANGE),
+            ("frequency_penalty", self.frequency_penalty, PENALTY_RANGE),
+        ] {
+            if let Some(value) = value.filter(|value| !range.contains(value)) {
+                return Err(format!(
+                    "{} must be between {} and {}, not {}",
+                    name,
+                    range.start(),
+                    range.end(),
+                    value
+                ));
+            }
+        }
+        if let Some(max_tokens) = self.max_tokens.filter(|&max| max < 1) {
+            return Err(format!("max_tokens must be at least 1, not {}", max_tokens));
+        }
+        Ok(())
+    }
+
+    /// The stop sequences, unless there are none.
+    pub fn stop_sequences(&self) -> Option<&[String]> {
+        self.stop.as_deref().filter(|stop| !stop.is_empty())
+    }
+}
+
+/// Parses `pg_summarizer.stop`: a JSON array of strings, or else a single
+/// stop sequence.
+pub fn parse_stop(value: &str) -> Result<Vec<String>, String> {
+    match serde_json::from_str::<Value>(value) {
+        Ok(Value::String(stop)) => Ok(vec![stop]),
+        Ok(Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                Value::String(stop) => Ok(stop),
+                _ => Err("Each stop sequence must be a JSON string.".to_string()),
+            })
+            .collect(),
+        _ => Ok(vec![value.to_string()]),
+    }
+}
+
+/// A JSON object of those of `fields` that have a value.
+pub fn present_fields<const N: usize>(fields: [(&str, Option<Value>); N]) -> Map<String, Value> {
+    fields
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect()
+}
@@ -0,0 +1,148 @@
+//! Named configurations stored in `summarize_profiles`.
+//!
+//! A profile bundles the provider, base URL, API key, model, prompt and
+//! generation parameters of one workload, so a call can select them all with
+//! `profile => 'legal_short'` instead of a series of `SET`s. Columns left NULL
+//! fall back to the `pg_summarizer.*` settings. The API key is never stored
+//! in the table: a profile names the setting that holds it.
+
+use crate::error::{ErrorKind, SummarizeError};
+use crate::guc;
+use crate::params::GenerationParams;
+use crate::providers::ProviderKind;
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+CREATE TABLE summarize_profiles (
+    name text PRIMARY KEY,
+    provider text CHECK (provider IN ('openai', 'anthropic', 'ollama', 'azure', 'gemini', 'bedrock')),
+    base_url text,
+    model text,
+    prompt text,
+    parameters jsonb NOT NULL DEFAULT '{}' CHECK (jsonb_typeof(parameters) = 'object'),
+    api_key_setting text,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+COMMENT ON TABLE summarize_profiles IS 'Named configurations selected with summarize(..., profile => name)';
+COMMENT ON COLUMN summarize_profiles.parameters IS
+    'Generation parameters, e.g. {"temperature": 0.2, "max_tokens": 300, "stop": ["END"]}';
+COMMENT ON COLUMN summarize_profiles.api_key_setting IS
+    'Name of the setting holding the API key, e.g. pg_summarizer.api_key';
+"#,
+    name = "summarize_profiles",
+);
+
+/// Where and how summaries are requested: a profile's columns over the
+/// settings.
+pub struct Profile {
+    pub provider: ProviderKind,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// Name of the setting `api_key` was read from, for error messages.
+    pub api_key_setting: String,
+    pub model: Option<String>,
+    pub prompt: Option<String>,
+    pub params: GenerationParams,
+}
+
+impl Profile {
+    /// Profile `name`, or `pg_summarizer.default_profile` if NULL, or just the
+    /// settings if neither is given.
+    pub fn configured(name: Option<&str>) -> Result<Self, SummarizeError> {
+        let settings = Profile {
+            provider: guc::PROVIDER.get(),
+            base_url: guc::BASE_URL.get(),
+            api_key: guc::API_KEY.get(),
+            api_key_setting: "pg_summarizer.api_key".to_string(),
+            model: guc::MODEL.get(),
+            prompt: guc::PROMPT.get(),
+            params: GenerationParams::configured(),
+        };
+        match name
+            .map(str::to_string)
+            .or_else(|| guc::DEFAULT_PROFILE.get())
+        {
+            Some(name) => Profile::load(&name, settings),
+            None => Ok(settings),
+        }
+    }
+
+    /// Reads profile `name`, taking what it leaves NULL from `settings`.
+    fn load(name: &str, settings: Profile) -> Result<Self, SummarizeError> {
+        let invalid =
+            |message: String| SummarizeError::new(ErrorKind::InvalidConfiguration, message);
+        let row = Spi::connect(|client| {
+            let mut rows = client.select(
+                "SELECT provider, nullif(base_url, ''), nullif(model, ''), nullif(prompt, ''), \
+                parameters, nullif(api_key_setting, '') FROM summarize_profiles WHERE name = $1",
+                Some(1),
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())]),
+            )?;
+            rows.next()
+                .map(|row| {
+                    Ok::<_, pgrx::spi::Error>((
+                        row.get::<String>(1)?,
+                        row.get::<String>(2)?,
+                        row.get::<String>(3)?,
+                        row.get::<String>(4)?,
+                        row.get::<pgrx::JsonB>(5)?,
+                        row.get::<String>(6)?,
+                    ))
+                })
+                .transpose()
+        })
+        .map_err(|e| invalid(format!("could not read profile \"{}\": {}", name, e)))?;
+        let Some((provider, base_url, model, prompt, parameters, api_key_setting)) = row else {
+            return Err(
+                invalid(format!("summarization profile \"{}\" does not exist", name)).with_hint(
+                    "Add it to summarize_profiles, or correct pg_summarizer.default_profile.",
+                ),
+            );
+        };
+
+        let provider = match provider {
+            Some(provider) => ProviderKind::parse(&provider)
+                .ok_or_else(|| invalid(format!("unknown provider \"{}\"", provider)))?,
+            None => settings.provider,
+        };
+        if let Some(base_url) = &base_url {
+            guc::validate_base_url(base_url).map_err(|detail| {
+                invalid(format!("invalid base_url in profile \"{}\"", name)).with_detail(detail)
+            })?;
+        }
+        let params = match parameters {
+            Some(pgrx::JsonB(parameters)) => {
+                GenerationParams::from_json(parameters).map_err(|e| {
+                    invalid(format!("invalid parameters in profile \"{}\": {}", name, e))
+                })?
+            }
+            None => GenerationParams::default(),
+        };
+        let (api_key, api_key_setting) = match api_key_setting {
+            // Superuser-only, so it cannot be read back with current_setting().
+            Some(setting) if setting == settings.api_key_setting => {
+                (settings.api_key, settings.api_key_setting)
+            }
+            Some(setting) => (
+                Spi::get_one_with_args::<String>(
+                    "SELECT nullif(current_setting($1, true), '')",
+                    vec![(PgBuiltInOids::TEXTOID.oid(), setting.clone().into_datum())],
+                )
+                .map_err(|e| invalid(format!("could not read setting {}: {}", setting, e)))?,
+                setting,
+            ),
+            None => (settings.api_key, settings.api_key_setting),
+        };
+
+        Ok(Profile {
+            provider,
+            base_url: base_url.or(settings.base_url),
+            api_key,
+            api_key_setting,
+            model: model.or(settings.model),
+            prompt: prompt.or(settings.prompt),
+            params: params.or(settings.params),
+        })
+    }
+}
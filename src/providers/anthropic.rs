@@ -0,0 +1,102 @@
+use super::{join_url, Provider, SummaryRequest};
+use crate::error::{ErrorKind, SummarizeError};
+use crate::{guc, params};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use std::ops::RangeInclusive;
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+
+/// The Messages API requires an explicit output limit.
+const DEFAULT_MAX_TOKENS: i32 = 1024;
+
+/// The Anthropic Messages API.
+pub struct Anthropic {
+    base_url: String,
+    api_key: String,
+    version: String,
+}
+
+impl Anthropic {
+    pub fn new(base_url: Option<String>, api_key: &str) -> Self {
+        Anthropic {
+            base_url: base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            api_key: api_key.to_string(),
+            version: guc::ANTHROPIC_VERSION.get().unwrap_or_default(),
+        }
+    }
+}
+
+impl Provider for Anthropic {
+    fn default_model(&self) -> &'static str {
+        "claude-3-haiku-20240307"
+    }
+
+    fn temperature_range(&self) -> RangeInclusive<f64> {
+        params::ANTHROPIC_TEMPERATURE_RANGE
+    }
+
+    fn endpoint(&self, _request: &SummaryRequest) -> String {
+        join_url(&self.base_url, "/messages")
+    }
+
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-api-key"),
+            HeaderValue::from_str(&self.api_key)?,
+        );
+        headers.insert(
+            HeaderName::from_static("anthropic-version"),
+            HeaderValue::from_str(&self.version)?,
+        );
+        Ok(headers)
+    }
+
+    /// Anthropic has no penalties or seed; those parameters are left out.
+    fn request_body(&self, request: &SummaryRequest) -> Value {
+        let params = request.params;
+        let mut body = json!({
+            "model": request.model,
+            "max_tokens": params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "system": request.prompt,
+            "messages": [
+                {
+                    "role": "user",
+                    "content": request.user_message()
+                }
+            ]
+        });
+        body.as_object_mut()
+            .expect("request body is an object")
+            .extend(params::present_fields([
+                ("temperature", params.temperature.map(Value::from)),
+                ("top_p", params.top_p.map(Value::from)),
+                ("stop_sequences", params.stop_sequences().map(Value::from)),
+            ]));
+        body
+    }
+
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError> {
+        let response_json: Value = serde_json::from_str(body)?;
+        let blocks = response_json["content"]
+            .as_array()
+            .ok_or_else(SummarizeError::unexpected_format)?;
+        if response_json["stop_reason"] == "refusal" {
+            return Err(SummarizeError::new(
+                ErrorKind::Refused,
+                "Anthropic refused to summarize the input",
+            ));
+        }
+        let summary: String = blocks
+            .iter()
+            .filter(|block| block["type"] == "text")
+            .filter_map(|block| block["text"].as_str())
+            .collect();
+        if summary.is_empty() {
+            Err(SummarizeError::unexpected_format())
+        } else {
+            Ok(summary)
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use super::{openai, Provider, SummaryRequest};
+use crate::error::{ErrorKind, SummarizeError};
+use crate::guc;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use serde_json::Value;
+
+/// An Azure OpenAI resource, addressed by deployment rather than by model.
+pub struct AzureOpenAi {
+    endpoint: Url,
+    deployment: Option<String>,
+    api_version: String,
+    api_key: String,
+}
+
+impl AzureOpenAi {
+    pub fn new(base_url: Option<String>, api_key: &str) -> Result<Self, SummarizeError> {
+        let base_url = base_url.ok_or_else(|| {
+            SummarizeError::new(
+                ErrorKind::InvalidConfiguration,
+                "pg_summarizer.base_url must be set to the Azure OpenAI resource endpoint",
+            )
+        })?;
+        let endpoint = Url::parse(&base_url)
+            .map_err(|e| SummarizeError::new(ErrorKind::InvalidConfiguration, e.to_string()))?;
+        Ok(AzureOpenAi {
+            endpoint,
+            deployment: guc::AZURE_DEPLOYMENT.get(),
+            api_version: guc::AZURE_API_VERSION.get().unwrap_or_default(),
+            api_key: api_key.to_string(),
+        })
+    }
+}
+
+impl Provider for AzureOpenAi {
+    fn default_model(&self) -> &'static str {
+        "gpt-35-turbo"
+    }
+
+    /// Deployments are commonly named after the model they serve, so the model
+    /// stands in for the deployment name when none is configured.
+    fn endpoint(&self, request: &SummaryRequest) -> String {
+        let deployment = self.deployment.as_deref().unwrap_or(request.model);
+        let mut url = self.endpoint.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend([
+                "openai",
+                "deployments",
+                deployment,
+                "chat",
+                "completions",
+            ]);
+        }
+        url.query_pairs_mut()
+            .append_pair("api-version", &self.api_version);
+        url.into()
+    }
+
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("api-key"),
+            HeaderValue::from_str(&self.api_key)?,
+        );
+        Ok(headers)
+    }
+
+    fn request_body(&self, request: &SummaryRequest) -> Value {
+        openai::chat_completions_body(request)
+    }
+
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError> {
+        openai::parse_chat_completion(body)
+    }
+}
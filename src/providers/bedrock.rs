@@ -0,0 +1,141 @@
+use super::sigv4::{self, Credentials};
+use super::{join_url, Provider, SummaryRequest};
+use crate::error::{ErrorKind, SummarizeError};
+use crate::{guc, params};
+use reqwest::header::HeaderMap;
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::ops::RangeInclusive;
+use std::time::SystemTime;
+
+/// The Amazon Bedrock Converse API, authenticated with SigV4.
+pub struct Bedrock {
+    base_url: String,
+    region: String,
+    credentials: Credentials,
+}
+
+impl Bedrock {
+    pub fn new(base_url: Option<String>) -> Result<Self, SummarizeError> {
+        let region = guc::AWS_REGION
+            .get()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .unwrap_or_else(|| "us-east-1".to_string());
+        // The environment is not checked like the setting is.
+        guc::validate_aws_region(&region).map_err(|detail| {
+            SummarizeError::new(
+                ErrorKind::InvalidConfiguration,
+                format!("invalid AWS region \"{}\"", region),
+            )
+            .with_detail(detail)
+        })?;
+        let credentials = Credentials::resolve(
+            guc::AWS_ACCESS_KEY_ID.get(),
+            guc::AWS_SECRET_ACCESS_KEY.get(),
+            guc::AWS_SESSION_TOKEN.get(),
+            guc::AWS_PROFILE.get(),
+        )
+        .map_err(|e| {
+            SummarizeError::new(ErrorKind::MissingCredentials, e.to_string()).with_hint(
+                "Set pg_summarizer.aws_access_key_id and pg_summarizer.aws_secret_access_key, \
+                or provide credentials through the server environment or ~/.aws/credentials.",
+            )
+        })?;
+        Ok(Bedrock {
+            base_url: base_url
+                .unwrap_or_else(|| format!("https://bedrock-runtime.{}.amazonaws.com", region)),
+            region,
+            credentials,
+        })
+    }
+}
+
+impl Provider for Bedrock {
+    fn default_model(&self) -> &'static str {
+        "anthropic.claude-3-haiku-20240307-v1:0"
+    }
+
+    fn temperature_range(&self) -> RangeInclusive<f64> {
+        params::ANTHROPIC_TEMPERATURE_RANGE
+    }
+
+    fn requires_api_key(&self) -> bool {
+        false
+    }
+
+    fn endpoint(&self, request: &SummaryRequest) -> String {
+        join_url(
+            &self.base_url,
+            &format!("/model/{}/converse", sigv4::uri_encode(request.model)),
+        )
+    }
+
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError> {
+        Ok(HeaderMap::new())
+    }
+
+    fn sign(&self, url: &str, headers: &mut HeaderMap, body: &[u8]) -> Result<(), SummarizeError> {
+        let url = Url::parse(url)
+            .map_err(|e| SummarizeError::new(ErrorKind::InvalidConfiguration, e.to_string()))?;
+        sigv4::sign(
+            "POST",
+            &url,
+            headers,
+            body,
+            &self.credentials,
+            &self.region,
+            "bedrock",
+            &sigv4::amz_date(SystemTime::now()),
+        )
+        .map_err(|e| SummarizeError::new(ErrorKind::InvalidConfiguration, e.to_string()))
+    }
+
+    /// The Converse API has no penalties or seed; those parameters are left
+    /// out.
+    fn request_body(&self, request: &SummaryRequest) -> Value {
+        let mut body = json!({
+            "system": [{ "text": request.prompt }],
+            "messages": [
+                {
+                    "role": "user",
+                    "content": [{ "text": request.user_message() }]
+                }
+            ]
+        });
+        let params = request.params;
+        let config = params::present_fields([
+            ("maxTokens", params.max_tokens.map(Value::from)),
+            ("temperature", params.temperature.map(Value::from)),
+            ("topP", params.top_p.map(Value::from)),
+            ("stopSequences", params.stop_sequences().map(Value::from)),
+        ]);
+        if !config.is_empty() {
+            body["inferenceConfig"] = Value::Object(config);
+        }
+        body
+    }
+
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError> {
+        let response_json: Value = serde_json::from_str(body)?;
+        if let Some(reason @ ("content_filtered" | "guardrail_intervened")) =
+            response_json["stopReason"].as_str()
+        {
+            return Err(SummarizeError::new(
+                ErrorKind::Refused,
+                format!("Bedrock returned no summary: {}", reason),
+            ));
+        }
+        let summary: String = response_json["output"]["message"]["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|block| block["text"].as_str())
+            .collect();
+        if summary.is_empty() {
+            Err(SummarizeError::unexpected_format())
+        } else {
+            Ok(summary)
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use super::{join_url, Provider, SummaryRequest};
+use crate::error::{ErrorKind, SummarizeError};
+use crate::params;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+
+const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// The Google Gemini generateContent API.
+pub struct Gemini {
+    base_url: String,
+    api_key: String,
+}
+
+impl Gemini {
+    pub fn new(base_url: Option<String>, api_key: &str) -> Self {
+        Gemini {
+            base_url: base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            api_key: api_key.to_string(),
+        }
+    }
+}
+
+impl Provider for Gemini {
+    fn default_model(&self) -> &'static str {
+        "gemini-1.5-flash"
+    }
+
+    fn endpoint(&self, request: &SummaryRequest) -> String {
+        let model = request.model.trim_start_matches("models/");
+        join_url(
+            &self.base_url,
+            &format!("/models/{}:generateContent", model),
+        )
+    }
+
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-goog-api-key"),
+            HeaderValue::from_str(&self.api_key)?,
+        );
+        Ok(headers)
+    }
+
+    fn request_body(&self, request: &SummaryRequest) -> Value {
+        let mut body = json!({
+            "systemInstruction": {
+                "parts": [{ "text": request.prompt }]
+            },
+            "contents": [
+                {
+                    "role": "user",
+                    "parts": [{ "text": request.user_message() }]
+                }
+            ]
+        });
+        let params = request.params;
+        let config = params::present_fields([
+            ("temperature", params.temperature.map(Value::from)),
+            ("maxOutputTokens", params.max_tokens.map(Value::from)),
+            ("topP", params.top_p.map(Value::from)),
+            ("presencePenalty", params.presence_penalty.map(Value::from)),
+            (
+                "frequencyPenalty",
+                params.frequency_penalty.map(Value::from),
+            ),
+            ("seed", params.seed.map(Value::from)),
+            ("stopSequences", params.stop_sequences().map(Value::from)),
+        ]);
+        if !config.is_empty() {
+            body["generationConfig"] = Value::Object(config);
+        }
+        body
+    }
+
+    /// A blocked prompt comes back without candidates and a blocked answer
+    /// without parts; both are reported with the reason Gemini gives.
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError> {
+        let response_json: Value = serde_json::from_str(body)?;
+        if let Some(reason) = response_json["promptFeedback"]["blockReason"].as_str() {
+            return Err(SummarizeError::new(
+                ErrorKind::Refused,
+                format!("Gemini blocked the prompt: {}", reason),
+            ));
+        }
+        let candidate = &response_json["candidates"][0];
+        let summary: String = candidate["content"]["parts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|part| part["text"].as_str())
+            .collect();
+        if !summary.is_empty() {
+            return Ok(summary);
+        }
+        match candidate["finishReason"].as_str() {
+            Some(reason) if reason != "STOP" => Err(SummarizeError::new(
+                ErrorKind::Refused,
+                format!("Gemini returned no text: {}", reason),
+            )),
+            _ => Err(SummarizeError::unexpected_format()),
+        }
+    }
+}
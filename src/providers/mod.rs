@@ -0,0 +1,161 @@
+//! The LLM APIs `summarize()` can talk to.
+//!
+//! Each provider knows where to send a request, how to authenticate it, how to
+//! shape the body and how to pull the summary out of the response. Everything
+//! else, sending the request and handling HTTP failures, lives in
+//! `make_api_call`.
+
+mod anthropic;
+mod azure;
+mod bedrock;
+mod gemini;
+mod ollama;
+mod openai;
+pub mod sigv4;
+
+pub use anthropic::Anthropic;
+pub use azure::AzureOpenAi;
+pub use bedrock::Bedrock;
+pub use gemini::Gemini;
+pub use ollama::Ollama;
+pub use openai::OpenAi;
+
+use crate::error::SummarizeError;
+use crate::params::{self, GenerationParams};
+use crate::tokens;
+use pgrx::PostgresGucEnum;
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+use std::ops::RangeInclusive;
+
+/// The provider-independent inputs of a single summarization call.
+pub struct SummaryRequest<'a> {
+    pub input: &'a str,
+    pub model: &'a str,
+    pub prompt: &'a str,
+    pub params: &'a GenerationParams,
+}
+
+impl SummaryRequest<'_> {
+    /// The user message sent to every provider.
+    pub fn user_message(&self) -> String {
+        format!("<text>{}</text>", self.input)
+    }
+}
+
+pub trait Provider {
+    /// Model used when `pg_summarizer.model` is not set.
+    fn default_model(&self) -> &'static str;
+
+    /// Whether `summarize()` must refuse to run without `pg_summarizer.api_key`.
+    fn requires_api_key(&self) -> bool {
+        true
+    }
+
+    /// URL the request is posted to.
+    fn endpoint(&self, request: &SummaryRequest) -> String;
+
+    /// Headers carrying the credentials, in whatever scheme the API expects.
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError>;
+
+    /// Adds a signature over the finished request, for APIs that authenticate
+    /// each request rather than accept a static credential.
+    fn sign(
+        &self,
+        _url: &str,
+        _headers: &mut HeaderMap,
+        _body: &[u8],
+    ) -> Result<(), SummarizeError> {
+        Ok(())
+    }
+
+    /// JSON body of the request.
+    fn request_body(&self, request: &SummaryRequest) -> Value;
+
+    /// Extracts the summary from the body of a successful response.
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError>;
+
+    /// Temperatures the API accepts.
+    fn temperature_range(&self) -> RangeInclusive<f64> {
+        params::TEMPERATURE_RANGE
+    }
+
+    /// Tokens `model` takes in one request, prompt and answer included, if
+    /// known.
+    fn context_window(&self, model: &str) -> Option<usize> {
+        tokens::context_window(model)
+    }
+}
+
+/// Values accepted by `pg_summarizer.provider`.
+#[allow(non_camel_case_types)]
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Debug)]
+pub enum ProviderKind {
+    openai,
+    anthropic,
+    ollama,
+    azure,
+    gemini,
+    bedrock,
+}
+
+impl ProviderKind {
+    /// The provider named `name`, as written in `pg_summarizer.provider`.
+    pub fn parse(name: &str) -> Option<Self> {
+        [
+            ProviderKind::openai,
+            ProviderKind::anthropic,
+            ProviderKind::ollama,
+            ProviderKind::azure,
+            ProviderKind::gemini,
+            ProviderKind::bedrock,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+    }
+
+    /// The name of this provider as written in `pg_summarizer.provider`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ProviderKind::openai => "openai",
+            ProviderKind::anthropic => "anthropic",
+            ProviderKind::ollama => "ollama",
+            ProviderKind::azure => "azure",
+            ProviderKind::gemini => "gemini",
+            ProviderKind::bedrock => "bedrock",
+        }
+    }
+}
+
+/// Builds the provider selected by `pg_summarizer.provider`, ignoring any
+/// profile; calls go through [`crate::profiles::Profile`] instead.
+#[cfg(any(test, feature = "pg_test"))]
+pub fn configured(api_key: &str) -> Result<Box<dyn Provider>, SummarizeError> {
+    build(
+        crate::guc::PROVIDER.get(),
+        crate::guc::BASE_URL.get(),
+        api_key,
+    )
+}
+
+/// Builds a provider of `kind`, sending requests to `base_url` instead of its
+/// default when given.
+pub fn build(
+    kind: ProviderKind,
+    base_url: Option<String>,
+    api_key: &str,
+) -> Result<Box<dyn Provider>, SummarizeError> {
+    Ok(match kind {
+        ProviderKind::openai => Box::new(OpenAi::new(base_url, api_key)),
+        ProviderKind::anthropic => Box::new(Anthropic::new(base_url, api_key)),
+        ProviderKind::ollama => Box::new(Ollama::new(base_url, api_key)),
+        ProviderKind::azure => Box::new(AzureOpenAi::new(base_url, api_key)?),
+        ProviderKind::gemini => Box::new(Gemini::new(base_url, api_key)),
+        ProviderKind::bedrock => Box::new(Bedrock::new(base_url)?),
+    })
+}
+
+/// Joins a base URL and an absolute path without doubling the slash between them.
+fn join_url(base_url: &str, path: &str) -> String {
+    format!("{}{}", base_url.trim_end_matches('/'), path)
+}
@@ -0,0 +1,132 @@
+use super::{join_url, Provider, SummaryRequest};
+use crate::error::{ErrorKind, SummarizeError};
+use crate::{guc, params};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde_json::{json, Value};
+
+const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// The chat API of a local or self-hosted Ollama server.
+pub struct Ollama {
+    base_url: String,
+    api_key: String,
+    stream: bool,
+    num_ctx: i32,
+    keep_alive: Option<String>,
+}
+
+impl Ollama {
+    pub fn new(base_url: Option<String>, api_key: &str) -> Self {
+        Ollama {
+            base_url: base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            api_key: api_key.to_string(),
+            stream: guc::OLLAMA_STREAM.get(),
+            num_ctx: guc::OLLAMA_NUM_CTX.get(),
+            keep_alive: guc::OLLAMA_KEEP_ALIVE.get(),
+        }
+    }
+}
+
+impl Provider for Ollama {
+    fn default_model(&self) -> &'static str {
+        "llama3"
+    }
+
+    fn requires_api_key(&self) -> bool {
+        false
+    }
+
+    fn endpoint(&self, _request: &SummaryRequest) -> String {
+        join_url(&self.base_url, "/api/chat")
+    }
+
+    /// Ollama itself is unauthenticated, but a key is still sent as a bearer
+    /// token when set, for servers running behind an authenticating proxy.
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError> {
+        let mut headers = HeaderMap::new();
+        if !self.api_key.is_empty() {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
+            );
+        }
+        Ok(headers)
+    }
+
+    fn request_body(&self, request: &SummaryRequest) -> Value {
+        let mut body = json!({
+            "model": request.model,
+            "stream": self.stream,
+            "messages": [
+                {
+                    "role": "system",
+                    "content": request.prompt
+                },
+                {
+                    "role": "user",
+                    "content": request.user_message()
+                }
+            ]
+        });
+        let params = request.params;
+        let options = params::present_fields([
+            (
+                "num_ctx",
+                (self.num_ctx > 0).then(|| Value::from(self.num_ctx)),
+            ),
+            ("temperature", params.temperature.map(Value::from)),
+            ("num_predict", params.max_tokens.map(Value::from)),
+            ("top_p", params.top_p.map(Value::from)),
+            ("presence_penalty", params.presence_penalty.map(Value::from)),
+            (
+                "frequency_penalty",
+                params.frequency_penalty.map(Value::from),
+            ),
+            ("seed", params.seed.map(Value::from)),
+            ("stop", params.stop_sequences().map(Value::from)),
+        ]);
+        if !options.is_empty() {
+            body["options"] = Value::Object(options);
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            // A bare number is a count of seconds, which Ollama only accepts
+            // as a JSON number; anything else is a duration such as "10m".
+            body["keep_alive"] = match keep_alive.parse::<i64>() {
+                Ok(seconds) => json!(seconds),
+                Err(_) => json!(keep_alive),
+            };
+        }
+        body
+    }
+
+    /// Accepts both a single JSON object and, when streaming, the
+    /// newline-delimited chunks whose message contents form the summary.
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError> {
+        let mut summary = String::new();
+        for line in body.lines().filter(|line| !line.trim().is_empty()) {
+            let chunk: Value = serde_json::from_str(line)?;
+            if let Some(error) = chunk["error"].as_str() {
+                return Err(SummarizeError::new(
+                    ErrorKind::Status,
+                    format!("Ollama returned an error: {}", error),
+                ));
+            }
+            match chunk["message"]["content"].as_str() {
+                Some(content) => summary.push_str(content),
+                None if chunk["done"] == true => {}
+                None => return Err(SummarizeError::unexpected_format()),
+            }
+        }
+        if summary.is_empty() {
+            Err(SummarizeError::unexpected_format())
+        } else {
+            Ok(summary)
+        }
+    }
+
+    /// Ollama cuts inputs to the context it was given instead of failing, so
+    /// only `pg_summarizer.ollama_num_ctx` is known to be the real window.
+    fn context_window(&self, _model: &str) -> Option<usize> {
+        (self.num_ctx > 0).then_some(self.num_ctx as usize)
+    }
+}
@@ -0,0 +1,124 @@
+use super::{join_url, Provider, SummaryRequest};
+use crate::error::{ErrorKind, SummarizeError};
+use crate::{guc, params};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde_json::{json, Value};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// The OpenAI chat completions API, or any server compatible with it.
+pub struct OpenAi {
+    base_url: String,
+    path: String,
+    api_key: String,
+}
+
+impl OpenAi {
+    pub fn new(base_url: Option<String>, api_key: &str) -> Self {
+        OpenAi {
+            base_url: base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            path: guc::CHAT_COMPLETIONS_PATH.get().unwrap_or_default(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    /// URL of another endpoint of the same API, e.g. `/files`.
+    pub fn url(&self, path: &str) -> String {
+        join_url(&self.base_url, path)
+    }
+}
+
+impl Provider for OpenAi {
+    fn default_model(&self) -> &'static str {
+        "gpt-3.5-turbo"
+    }
+
+    fn endpoint(&self, _request: &SummaryRequest) -> String {
+        join_url(&self.base_url, &self.path)
+    }
+
+    fn auth_headers(&self) -> Result<HeaderMap, SummarizeError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
+        );
+        Ok(headers)
+    }
+
+    fn request_body(&self, request: &SummaryRequest) -> Value {
+        chat_completions_body(request)
+    }
+
+    fn parse_response(&self, body: &str) -> Result<String, SummarizeError> {
+        parse_chat_completion(body)
+    }
+}
+
+/// Request body of the chat completions API, shared with Azure OpenAI.
+pub(super) fn chat_completions_body(request: &SummaryRequest) -> Value {
+    let mut body = json!({
+        "model": request.model,
+        "messages": [
+            {
+                "role": "system",
+                "content": request.prompt
+            },
+            {
+                "role": "user",
+                "content": request.user_message()
+            }
+        ]
+    });
+    let params = request.params;
+    // Reasoning models reject max_tokens, which other servers speaking the
+    // same API may not know the replacement of.
+    let max_tokens = if is_reasoning_model(request.model) {
+        "max_completion_tokens"
+    } else {
+        "max_tokens"
+    };
+    body.as_object_mut()
+        .expect("request body is an object")
+        .extend(params::present_fields([
+            ("temperature", params.temperature.map(Value::from)),
+            (max_tokens, params.max_tokens.map(Value::from)),
+            ("top_p", params.top_p.map(Value::from)),
+            ("presence_penalty", params.presence_penalty.map(Value::from)),
+            (
+                "frequency_penalty",
+                params.frequency_penalty.map(Value::from),
+            ),
+            ("seed", params.seed.map(Value::from)),
+            ("stop", params.stop_sequences().map(Value::from)),
+        ]));
+    body
+}
+
+fn is_reasoning_model(model: &str) -> bool {
+    ["o1", "o3", "o4"]
+        .iter()
+        .any(|family| model == *family || model.starts_with(&format!("{}-", family)))
+}
+
+pub(super) fn parse_chat_completion(body: &str) -> Result<String, SummarizeError> {
+    let response_json: Value = serde_json::from_str(body)?;
+    let choice = &response_json["choices"][0];
+    if let Some(refusal) = choice["message"]["refusal"].as_str() {
+        return Err(SummarizeError::new(
+            ErrorKind::Refused,
+            format!("provider refused to summarize the input: {}", refusal),
+        ));
+    }
+    if choice["finish_reason"] == "content_filter" {
+        return Err(SummarizeError::new(
+            ErrorKind::Refused,
+            "provider content filter blocked the summary",
+        ));
+    }
+    if let Some(summary) = choice["message"]["content"].as_str() {
+        Ok(summary.to_string())
+    } else {
+        Err(SummarizeError::unexpected_format())
+    }
+}
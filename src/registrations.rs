@@ -0,0 +1,267 @@
+//! Columns kept summarized by triggers, set up with `summarize_register()`.
+//!
+//! The trigger only queues a job in `summarize_jobs` and clears the stale
+//! summary; the background workers summarize the text and write it back to
+//! the row, found again by its primary key. A summary is only written if the
+//! source column still holds the text it was made from, so a slow job never
+//! overwrites the summary of a newer text.
+
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+CREATE TABLE summarize_registrations (
+    relation regclass NOT NULL,
+    source_column text NOT NULL,
+    target_column text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (relation, target_column)
+);
+COMMENT ON TABLE summarize_registrations IS 'Columns maintained by summarize_register()';
+
+-- Arguments: the source column, the target column, then the primary key columns.
+-- Runs with the rights of the extension's owner, so writers of a registered
+-- table need none on summarize_jobs.
+CREATE FUNCTION summarize_queue_trigger() RETURNS trigger LANGUAGE plpgsql
+SECURITY DEFINER SET search_path = pg_catalog, @extschema@ AS $$
+DECLARE
+    input text := to_jsonb(NEW) ->> TG_ARGV[0];
+BEGIN
+    IF TG_OP = 'UPDATE' AND input IS NOT DISTINCT FROM to_jsonb(OLD) ->> TG_ARGV[0] THEN
+        RETURN NEW;
+    END IF;
+    -- The summary of the previous text no longer applies.
+    NEW := jsonb_populate_record(NEW, jsonb_build_object(TG_ARGV[1], NULL));
+    IF input IS NOT NULL THEN
+        INSERT INTO summarize_jobs
+            (input, model, prompt, target_relation, source_column, target_column, target_key)
+        SELECT input,
+            nullif(current_setting('pg_summarizer.model', true), ''),
+            nullif(current_setting('pg_summarizer.prompt', true), ''),
+            TG_RELID, TG_ARGV[0], TG_ARGV[1], jsonb_object_agg(key, value)
+        FROM jsonb_each(to_jsonb(NEW))
+        WHERE key = ANY (TG_ARGV[2:]);
+    END IF;
+    RETURN NEW;
+END
+$$;
+
+-- Writes the summary of a registered job to its row; returns the error
+-- message if that failed, e.g. because the table was dropped. Only columns
+-- still registered are written, whatever the job row names.
+CREATE FUNCTION summarize_write_back(job_id bigint, summary text) RETURNS text
+LANGUAGE plpgsql AS $$
+DECLARE
+    job summarize_jobs;
+BEGIN
+    SELECT * INTO job FROM summarize_jobs WHERE id = job_id;
+    IF NOT EXISTS (
+        SELECT FROM summarize_registrations AS r
+        WHERE r.relation = job.target_relation
+            AND r.source_column = job.source_column
+            AND r.target_column = job.target_column
+    ) THEN
+        RETURN format('column %I of relation %s is not registered for summaries',
+            job.target_column, job.target_relation);
+    END IF;
+    EXECUTE format(
+        'UPDATE %1$s AS t SET %2$I = $1 FROM jsonb_populate_record(NULL::%1$s, $2) AS k '
+        'WHERE %3$s AND t.%4$I::text IS NOT DISTINCT FROM $3',
+        job.target_relation,
+        job.target_column,
+        (SELECT string_agg(format('t.%1$I = k.%1$I', key), ' AND ')
+            FROM jsonb_object_keys(job.target_key) AS key),
+        job.source_column)
+    USING summary, job.target_key, job.input;
+    RETURN NULL;
+EXCEPTION WHEN OTHERS THEN
+    RETURN SQLERRM;
+END
+$$;
+"#,
+    name = "summarize_registrations",
+    requires = ["summarize_jobs"],
+);
+
+/// Keeps `target` of `relation` summarized from `source`: inserts and updates
+/// of `source` queue a job that writes the summary to `target`. With
+/// `backfill`, rows with text but no summary yet are queued too. Returns the
+/// number of rows queued.
+#[pg_extern]
+fn summarize_register(
+    relation: &str,
+    source: &str,
+    target: &str,
+    backfill: default!(bool, false),
+) -> i64 {
+    for column in [source, target] {
+        require_column(relation, column);
+    }
+    if source == target {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            "source and target must be different columns"
+        );
+    }
+
+    let key = Spi::get_one_with_args::<Vec<String>>(
+        "SELECT array_agg(a.attname::text ORDER BY a.attnum) FROM pg_index AS i \
+        JOIN pg_attribute AS a ON a.attrelid = i.indrelid AND a.attnum = ANY (i.indkey) \
+        WHERE i.indrelid = $1::regclass AND i.indisprimary",
+        args(&[relation]),
+    )
+    .unwrap_or_else(|e| error!("could not look up primary key: {}", e));
+    let Some(key) = key else {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_TABLE_DEFINITION,
+            format!("relation \"{}\" has no primary key", relation),
+            "Summaries are written back to the row they were made from, found by its primary key."
+        );
+    };
+    if key.iter().any(|column| column == target) {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("target column \"{}\" is part of the primary key", target)
+        );
+    }
+
+    let mut trigger_args = args(&[relation, source, target]);
+    trigger_args.push((PgBuiltInOids::TEXTARRAYOID.oid(), key.clone().into_datum()));
+    let create_trigger = Spi::get_one_with_args::<String>(
+        "SELECT format('DROP TRIGGER IF EXISTS %1$I ON %2$s; \
+        CREATE TRIGGER %1$I BEFORE INSERT OR UPDATE OF %3$I ON %2$s \
+        FOR EACH ROW EXECUTE FUNCTION summarize_queue_trigger(%4$s)', \
+        'summarize_' || $3, $1::regclass, $2, \
+        (SELECT string_agg(quote_literal(arg), ', ') FROM unnest(ARRAY[$2, $3] || $4) AS arg))",
+        trigger_args,
+    )
+    .unwrap_or_else(|e| error!("could not create trigger: {}", e))
+    .unwrap_or_default();
+    Spi::run(&create_trigger)
+        .and_then(|()| {
+            Spi::run_with_args(
+                "INSERT INTO summarize_registrations (relation, source_column, target_column) \
+                VALUES ($1::regclass, $2, $3) ON CONFLICT (relation, target_column) \
+                DO UPDATE SET source_column = EXCLUDED.source_column, created_at = now()",
+                Some(args(&[relation, source, target])),
+            )
+        })
+        .unwrap_or_else(|e| error!("could not register summary column: {}", e));
+
+    if !backfill {
+        return 0;
+    }
+    let queue_existing = Spi::get_one_with_args::<String>(
+        "SELECT format('WITH queued AS (INSERT INTO summarize_jobs \
+        (input, model, prompt, target_relation, source_column, target_column, target_key) \
+        SELECT t.%2$I::text, $1, $2, %1$L::regclass, %2$L, %3$L, \
+        (SELECT jsonb_object_agg(key, value) FROM jsonb_each(to_jsonb(t)) WHERE key = ANY ($3)) \
+        FROM %1$s AS t WHERE t.%2$I IS NOT NULL AND t.%3$I IS NULL RETURNING 1) \
+        SELECT count(*) FROM queued', $1::regclass, $2, $3)",
+        args(&[relation, source, target]),
+    )
+    .unwrap_or_else(|e| error!("could not queue existing rows: {}", e))
+    .unwrap_or_default();
+    Spi::get_one_with_args::<i64>(
+        &queue_existing,
+        vec![
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                crate::guc::MODEL.get().into_datum(),
+            ),
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                crate::guc::PROMPT.get().into_datum(),
+            ),
+            (PgBuiltInOids::TEXTARRAYOID.oid(), key.into_datum()),
+        ],
+    )
+    .unwrap_or_else(|e| error!("could not queue existing rows: {}", e))
+    .unwrap_or_default()
+}
+
+/// Stops maintaining `target` of `relation`, or every registered column of
+/// `relation` if `target` is NULL, and drops the jobs still queued for it.
+/// Summaries already written are kept. Returns the number of columns
+/// unregistered.
+#[pg_extern]
+fn summarize_unregister(relation: &str, target: default!(Option<&str>, "NULL")) -> i64 {
+    let targets = Spi::connect(|mut client| {
+        client
+            .update(
+                "DELETE FROM summarize_registrations \
+                WHERE relation = $1::regclass AND ($2::text IS NULL OR target_column = $2) \
+                RETURNING target_column",
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), relation.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), target.into_datum()),
+                ]),
+            )?
+            .map(|row| row.get::<String>(1).map(Option::unwrap_or_default))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .unwrap_or_else(|e| error!("could not unregister summary column: {}", e));
+
+    for target in &targets {
+        let drop_trigger = Spi::get_one_with_args::<String>(
+            "SELECT format('DROP TRIGGER IF EXISTS %I ON %s', 'summarize_' || $2, $1::regclass)",
+            args(&[relation, target]),
+        )
+        .unwrap_or_else(|e| error!("could not drop trigger: {}", e))
+        .unwrap_or_default();
+        Spi::run(&drop_trigger)
+            .and_then(|()| {
+                Spi::run_with_args(
+                    "DELETE FROM summarize_jobs WHERE status = 'queued' \
+                    AND target_relation = $1::regclass AND target_column = $2",
+                    Some(args(&[relation, target])),
+                )
+            })
+            .unwrap_or_else(|e| error!("could not unregister summary column: {}", e));
+    }
+    targets.len() as i64
+}
+
+/// Writes the summary of job `id` to the registered column it was queued
+/// for. Returns the error message if the row could not be updated.
+pub fn write_back(id: i64, summary: &str) -> Option<String> {
+    Spi::get_one_with_args::<String>(
+        "SELECT summarize_write_back($1, $2)",
+        vec![
+            (PgBuiltInOids::INT8OID.oid(), id.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), summary.into_datum()),
+        ],
+    )
+    .unwrap_or_else(|e| error!("could not write summary of job {}: {}", id, e))
+}
+
+/// Raises an error unless `relation` has a column named `column`.
+pub fn require_column(relation: &str, column: &str) {
+    let exists = Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT FROM pg_attribute \
+        WHERE attrelid = $1::regclass AND attname = $2 AND attnum > 0 AND NOT attisdropped)",
+        args(&[relation, column]),
+    )
+    .unwrap_or_else(|e| error!("could not look up column: {}", e));
+    if exists != Some(true) {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_UNDEFINED_COLUMN,
+            format!(
+                "column \"{}\" of relation \"{}\" does not exist",
+                column, relation
+            )
+        );
+    }
+}
+
+fn args(values: &[&str]) -> Vec<(PgOid, Option<pg_sys::Datum>)> {
+    values
+        .iter()
+        .map(|&value| (PgBuiltInOids::TEXTOID.oid(), value.into_datum()))
+        .collect()
+}
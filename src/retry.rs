@@ -0,0 +1,125 @@
+//! Retrying provider calls that fail for transient reasons.
+//!
+//! Rate limits (HTTP 429), server errors (5xx) and transport failures are
+//! retried with exponential backoff and jitter, unless the provider says how
+//! long to wait through `Retry-After` or OpenAI's `x-ratelimit-reset-*`
+//! headers. Waiting happens on the backend's HTTP runtime, which keeps
+//! processing interrupts, so a query cancel or `statement_timeout` ends it
+//! immediately.
+
+use crate::error::SummarizeError;
+use crate::guc;
+use reqwest::header::HeaderMap;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Upper bound for a single backoff delay, however many attempts were made.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_time: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// The policy described by the `pg_summarizer.retry_*` settings.
+    pub fn configured() -> Self {
+        let millis = |value: i32| Duration::from_millis(value.max(0) as u64);
+        RetryPolicy {
+            max_attempts: guc::MAX_ATTEMPTS.get().max(1) as u32,
+            base_delay: millis(guc::RETRY_BASE_DELAY.get()),
+            max_time: Some(millis(guc::RETRY_MAX_TIME.get())).filter(|t| !t.is_zero()),
+        }
+    }
+
+    /// Calls `attempt` until it succeeds, fails for good, or the attempts or
+    /// time allowed run out; the last error is returned in the latter cases.
+    pub async fn run<T, F>(&self, mut attempt: impl FnMut() -> F) -> Result<T, SummarizeError>
+    where
+        F: Future<Output = Result<T, SummarizeError>>,
+    {
+        let deadline = self.max_time.map(|max_time| Instant::now() + max_time);
+        let mut attempts = 1;
+        loop {
+            match attempt().await {
+                Err(e) if e.is_retryable() && attempts < self.max_attempts => {
+                    let delay = e.retry_after().unwrap_or_else(|| self.backoff(attempts));
+                    if deadline.is_some_and(|deadline| Instant::now() + delay > deadline) {
+                        return Err(e);
+                    }
+                    tokio::time::sleep(delay).await;
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Delay before the attempt after `attempts` failed ones: the base delay
+    /// doubled for each failure, with up to half of it taken off at random.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << (attempts - 1).min(16))
+            .min(MAX_BACKOFF);
+        let half = ceiling / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+}
+
+/// How long the provider asked us to wait, if it did.
+///
+/// `retry-after-ms` and `Retry-After` (in seconds or as an HTTP date) are
+/// taken as is. Otherwise the reset time of whichever OpenAI rate limit is
+/// exhausted, e.g. `x-ratelimit-reset-tokens: 6m0s`, is used.
+pub fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(millis) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Duration::try_from_secs_f64(millis / 1000.0).ok();
+    }
+    if let Some(value) = header("retry-after") {
+        return match value.trim().parse::<u64>() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => httpdate::parse_http_date(value.trim())
+                .ok()
+                .map(|date| date.duration_since(now).unwrap_or_default()),
+        };
+    }
+    ["requests", "tokens"]
+        .into_iter()
+        .filter(|limit| header(&format!("x-ratelimit-remaining-{}", limit)) == Some("0"))
+        .filter_map(|limit| header(&format!("x-ratelimit-reset-{}", limit)))
+        .filter_map(parse_duration)
+        .max()
+}
+
+/// Parses a Go-style duration such as `1s`, `6m0s` or `20ms`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut rest = value.trim();
+    let mut total = Duration::ZERO;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let amount: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let (unit, seconds) = [
+            ("ns", 1e-9),
+            ("us", 1e-6),
+            ("µs", 1e-6),
+            ("ms", 1e-3),
+            ("s", 1.0),
+            ("m", 60.0),
+            ("h", 3600.0),
+        ]
+        .into_iter()
+        .filter(|(unit, _)| rest.starts_with(unit))
+        .max_by_key(|(unit, _)| unit.len())?;
+        total += Duration::try_from_secs_f64(amount * seconds).ok()?;
+        rest = &rest[unit.len()..];
+    }
+    Some(total)
+}
@@ -0,0 +1,279 @@
+//! A bounded shared-memory LRU of recent summaries, in front of the
+//! `summarize_cache` table.
+//!
+//! It only exists when the extension is loaded through
+//! `shared_preload_libraries`, sized by `pg_summarizer.shared_cache_size`.
+//! Entries live in fixed-size slots grouped into sets of [`WAYS`]; a key can
+//! only be stored in the set its hash selects, and the least recently used
+//! slot of that set is replaced when it is full. Summaries too long for a slot
+//! are left to the table.
+
+use crate::guc;
+use pgrx::prelude::*;
+use pgrx::PgSharedMemoryInitialization;
+use std::ffi::CStr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NAME: &CStr = c"pg_summarize shared cache";
+const WAYS: usize = 8;
+const SLOT_TEXT_BYTES: usize = 4000;
+
+/// Index of `AddinShmemInitLock` in `MainLWLockArray`, as numbered in
+/// `storage/lwlocknames.h`; the same in every supported PostgreSQL version.
+const ADDIN_SHMEM_INIT_LOCK: usize = 21;
+
+pub static SHARED_CACHE: SharedCache = SharedCache {
+    header: AtomicPtr::new(std::ptr::null_mut()),
+    lock: AtomicPtr::new(std::ptr::null_mut()),
+};
+
+#[repr(C)]
+struct Header {
+    sets: usize,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    used: AtomicU64,
+}
+
+#[repr(C)]
+struct Slot {
+    key: [u8; 32],
+    valid: bool,
+    len: u32,
+    stored_at: u64,
+    last_used: AtomicU64,
+    text: [u8; SLOT_TEXT_BYTES],
+}
+
+pub struct SharedCache {
+    header: AtomicPtr<Header>,
+    lock: AtomicPtr<pg_sys::LWLock>,
+}
+
+pub struct Stats {
+    pub slots: i64,
+    pub used: i64,
+    pub hits: i64,
+    pub misses: i64,
+}
+
+impl SharedCache {
+    /// Bytes needed for the header and `sets` sets of slots.
+    fn size(sets: usize) -> usize {
+        std::mem::size_of::<Header>() + sets * WAYS * std::mem::size_of::<Slot>()
+    }
+
+    /// How many whole sets fit in `pg_summarizer.shared_cache_size`.
+    fn configured_sets() -> usize {
+        let bytes = guc::SHARED_CACHE_SIZE.get() as usize * 1024 * 1024;
+        bytes / (WAYS * std::mem::size_of::<Slot>())
+    }
+
+    /// Whether `pg_init` should run: only while preloading, and only when
+    /// the cache is given room for at least one set.
+    pub fn wanted() -> bool {
+        let preloading = unsafe { pg_sys::process_shared_preload_libraries_in_progress };
+        preloading && Self::configured_sets() > 0
+    }
+
+    /// Runs `f` on the header and the slots with the lock held in `mode`;
+    /// returns None when there is no shared cache.
+    fn locked<T>(
+        &self,
+        mode: pg_sys::LWLockMode,
+        f: impl FnOnce(&Header, *mut Slot, usize) -> T,
+    ) -> Option<T> {
+        let header = self.header.load(Ordering::Acquire);
+        if header.is_null() {
+            return None;
+        }
+        unsafe {
+            let lock = self.lock.load(Ordering::Acquire);
+            pg_sys::LWLockAcquire(lock, mode);
+            let result = f(&*header, header.add(1) as *mut Slot, (*header).sets * WAYS);
+            pg_sys::LWLockRelease(lock);
+            Some(result)
+        }
+    }
+
+    fn with_shared<T>(&self, f: impl FnOnce(&Header, &[Slot]) -> T) -> Option<T> {
+        self.locked(pg_sys::LWLockMode_LW_SHARED, |header, slots, len| {
+            f(header, unsafe { std::slice::from_raw_parts(slots, len) })
+        })
+    }
+
+    fn with_exclusive<T>(&self, f: impl FnOnce(&Header, &mut [Slot]) -> T) -> Option<T> {
+        self.locked(pg_sys::LWLockMode_LW_EXCLUSIVE, |header, slots, len| {
+            f(header, unsafe {
+                std::slice::from_raw_parts_mut(slots, len)
+            })
+        })
+    }
+
+    /// Returns the summary stored under `key` unless it is older than `ttl`
+    /// seconds (zero for no limit).
+    pub fn lookup(&self, key: &[u8; 32], ttl: u64) -> Option<String> {
+        let now = unix_time();
+        self.with_shared(|header, slots| {
+            let found = slots[set_of(header, key)].iter().find(|slot| {
+                slot.valid && slot.key == *key && (ttl == 0 || slot.stored_at + ttl > now)
+            });
+            match found {
+                Some(slot) => {
+                    let tick = header.clock.fetch_add(1, Ordering::Relaxed);
+                    slot.last_used.store(tick, Ordering::Relaxed);
+                    header.hits.fetch_add(1, Ordering::Relaxed);
+                    let text = &slot.text[..slot.len as usize];
+                    Some(String::from_utf8_lossy(text).into_owned())
+                }
+                None => {
+                    header.misses.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+            }
+        })
+        .flatten()
+    }
+
+    /// Stores `summary` under `key`, replacing the least recently used slot of
+    /// its set if needed.
+    pub fn store(&self, key: &[u8; 32], summary: &str) {
+        if summary.len() > SLOT_TEXT_BYTES {
+            return;
+        }
+        let now = unix_time();
+        self.with_exclusive(|header, slots| {
+            let set = &mut slots[set_of(header, key)];
+            let index = set
+                .iter()
+                .position(|slot| slot.valid && slot.key == *key)
+                .or_else(|| set.iter().position(|slot| !slot.valid))
+                .unwrap_or_else(|| {
+                    (0..set.len())
+                        .min_by_key(|&i| set[i].last_used.load(Ordering::Relaxed))
+                        .unwrap_or_default()
+                });
+            let slot = &mut set[index];
+            if !slot.valid {
+                header.used.fetch_add(1, Ordering::Relaxed);
+            }
+            slot.key = *key;
+            slot.valid = true;
+            slot.len = summary.len() as u32;
+            slot.stored_at = now;
+            slot.last_used.store(
+                header.clock.fetch_add(1, Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            slot.text[..summary.len()].copy_from_slice(summary.as_bytes());
+        });
+    }
+
+    /// Drops every entry.
+    pub fn clear(&self) {
+        self.with_exclusive(|header, slots| {
+            for slot in slots {
+                slot.valid = false;
+            }
+            header.used.store(0, Ordering::Relaxed);
+        });
+    }
+
+    pub fn stats(&self) -> Option<Stats> {
+        self.with_shared(|header, slots| Stats {
+            slots: slots.len() as i64,
+            used: header.used.load(Ordering::Relaxed) as i64,
+            hits: header.hits.load(Ordering::Relaxed) as i64,
+            misses: header.misses.load(Ordering::Relaxed) as i64,
+        })
+    }
+}
+
+impl PgSharedMemoryInitialization for SharedCache {
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(Self::size(Self::configured_sets()));
+            pg_sys::RequestNamedLWLockTranche(NAME.as_ptr(), 1);
+        }
+    }
+
+    fn shmem_init(&'static self) {
+        let sets = Self::configured_sets();
+        unsafe {
+            let addin_shmem_init_lock: *mut pg_sys::LWLock =
+                &mut (*pg_sys::MainLWLockArray.add(ADDIN_SHMEM_INIT_LOCK)).lock;
+            pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+
+            let mut found = false;
+            let header =
+                pg_sys::ShmemInitStruct(NAME.as_ptr(), Self::size(sets), &mut found) as *mut Header;
+            if !found {
+                // Zeroed slots are all invalid.
+                std::ptr::write_bytes(header as *mut u8, 0, Self::size(sets));
+                (*header).sets = sets;
+            }
+            self.lock.store(
+                &mut (*pg_sys::GetNamedLWLockTranche(NAME.as_ptr())).lock,
+                Ordering::Release,
+            );
+            self.header.store(header, Ordering::Release);
+
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+    }
+}
+
+/// The indexes of the slots `key` may be stored in.
+fn set_of(header: &Header, key: &[u8; 32]) -> Range<usize> {
+    let hash = u64::from_be_bytes(key[..8].try_into().unwrap());
+    let set = (hash % header.sets as u64) as usize;
+    set * WAYS..(set + 1) * WAYS
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Reports the size, occupancy and hit counts of the shared cache; all zero
+/// when the extension was not loaded through `shared_preload_libraries`.
+#[pg_extern]
+fn summarize_shared_cache_stats() -> TableIterator<
+    'static,
+    (
+        name!(slots, i64),
+        name!(used, i64),
+        name!(hits, i64),
+        name!(misses, i64),
+        name!(hit_rate, Option<f64>),
+    ),
+> {
+    let stats = SHARED_CACHE.stats().unwrap_or(Stats {
+        slots: 0,
+        used: 0,
+        hits: 0,
+        misses: 0,
+    });
+    let lookups = stats.hits + stats.misses;
+    TableIterator::once((
+        stats.slots,
+        stats.used,
+        stats.hits,
+        stats.misses,
+        (lookups > 0).then(|| stats.hits as f64 / lookups as f64),
+    ))
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW summarize_shared_cache AS SELECT * FROM summarize_shared_cache_stats();
+COMMENT ON VIEW summarize_shared_cache IS 'Occupancy and hit rate of the shared-memory summary cache';
+"#,
+    name = "summarize_shared_cache",
+    requires = [summarize_shared_cache_stats],
+);
@@ -0,0 +1,182 @@
+//! Length, format, audience, tone and language of a summary.
+//!
+//! Each is turned into an instruction from a built-in template and appended
+//! to the system prompt, so a variation of the summary does not need a prompt
+//! of its own. Like the generation parameters, each comes from its
+//! `pg_summarizer.*` setting unless given as an argument of `summarize()`.
+
+use crate::guc;
+use crate::language::OutputLanguage;
+
+/// How long a summary may be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Length {
+    Words(u32),
+    Sentences(u32),
+    Characters(u32),
+}
+
+impl Length {
+    /// Parses a count and a unit, such as `50 words`, `3 sentences` or
+    /// `280 characters`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "length must be a number followed by words, sentences or characters, not \"{}\"",
+                value
+            )
+        };
+        let mut parts = value.split_whitespace();
+        let (Some(count), Some(unit), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let count = count
+            .parse::<u32>()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(invalid)?;
+        match unit.to_lowercase().as_str() {
+            "word" | "words" => Ok(Length::Words(count)),
+            "sentence" | "sentences" => Ok(Length::Sentences(count)),
+            "character" | "characters" | "chars" => Ok(Length::Characters(count)),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn instruction(self) -> String {
+        match self {
+            Length::Words(count) => format!("Use at most {} words.", count),
+            Length::Sentences(1) => "Use a single sentence.".to_string(),
+            Length::Sentences(count) => format!("Use at most {} sentences.", count),
+            Length::Characters(count) => format!(
+                "Use at most {} characters, counting spaces and punctuation.",
+                count
+            ),
+        }
+    }
+}
+
+/// The shape of a summary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Paragraph,
+    Bullets,
+    Tldr,
+    Headline,
+    Abstract,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "paragraph" => Ok(Format::Paragraph),
+            "bullets" => Ok(Format::Bullets),
+            "tldr" | "tl;dr" => Ok(Format::Tldr),
+            "headline" => Ok(Format::Headline),
+            "abstract" => Ok(Format::Abstract),
+            _ => Err(format!(
+                "format must be paragraph, bullets, tldr, headline or abstract, not \"{}\"",
+                value
+            )),
+        }
+    }
+
+    fn instruction(self) -> &'static str {
+        match self {
+            Format::Paragraph => "Write the summary as a single paragraph of prose.",
+            Format::Bullets => {
+                "Write the summary as a list of bullet points, one key point per line, \
+                each line starting with \"- \"."
+            }
+            Format::Tldr => {
+                "Write a TL;DR: the gist of the text in one or two short sentences, \
+                without an introduction."
+            }
+            Format::Headline => {
+                "Write the summary as a single headline, without a trailing period or \
+                quotation marks."
+            }
+            Format::Abstract => {
+                "Write the summary as an abstract in one formal paragraph: the purpose, \
+                the main points and the conclusion of the text."
+            }
+        }
+    }
+}
+
+/// The length, format, audience, tone and language asked of a summary;
+/// `None` leaves it to the prompt.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SummaryStyle {
+    pub length: Option<Length>,
+    pub format: Option<Format>,
+    pub audience: Option<String>,
+    pub tone: Option<String>,
+    pub language: Option<OutputLanguage>,
+}
+
+impl SummaryStyle {
+    /// The style given as arguments of `summarize()`.
+    pub fn parse(
+        length: Option<&str>,
+        format: Option<&str>,
+        audience: Option<&str>,
+        tone: Option<&str>,
+        language: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(SummaryStyle {
+            length: length.map(Length::parse).transpose()?,
+            format: format.map(Format::parse).transpose()?,
+            audience: audience.map(str::to_string),
+            tone: tone.map(str::to_string),
+            language: language.map(OutputLanguage::parse).transpose()?,
+        })
+    }
+
+    /// The style set with `pg_summarizer.*` settings. Their check hooks have
+    /// already rejected values that do not parse.
+    pub fn configured() -> Self {
+        SummaryStyle {
+            length: guc::LENGTH.get().and_then(|v| Length::parse(&v).ok()),
+            format: guc::FORMAT.get().and_then(|v| Format::parse(&v).ok()),
+            audience: guc::AUDIENCE.get(),
+            tone: guc::TONE.get(),
+            language: guc::OUTPUT_LANGUAGE
+                .get()
+                .and_then(|v| OutputLanguage::parse(&v).ok()),
+        }
+    }
+
+    /// This style, with what it does not give taken from `fallback`.
+    pub fn or(self, fallback: SummaryStyle) -> Self {
+        SummaryStyle {
+            length: self.length.or(fallback.length),
+            format: self.format.or(fallback.format),
+            audience: self.audience.or(fallback.audience),
+            tone: self.tone.or(fallback.tone),
+            language: self.language.or(fallback.language),
+        }
+    }
+
+    /// `prompt` followed by the instructions for this style.
+    pub fn apply(&self, prompt: &str) -> String {
+        let instructions: Vec<String> = [
+            self.format.map(|format| format.instruction().to_string()),
+            self.length.map(Length::instruction),
+            self.audience
+                .as_ref()
+                .map(|audience| format!("Write for this audience: {}.", audience)),
+            self.tone
+                .as_ref()
+                .map(|tone| format!("Use this tone: {}.", tone)),
+            self.language.map(OutputLanguage::instruction),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if instructions.is_empty() {
+            return prompt.to_string();
+        }
+        format!("{}\n\n{}", prompt, instructions.join(" "))
+    }
+}
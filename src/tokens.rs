@@ -0,0 +1,200 @@
+//! Measuring and cutting input text in tokens, the unit context windows are
+//! given in.
+//!
+//! Tokens are counted locally with the BPE encodings OpenAI models use, bundled
+//! with the extension. Models of other vendors are counted with cl100k_base,
+//! which comes within a few percent of their own tokenizers for English text.
+//! Cuts are made on paragraph, sentence or word boundaries where there is one
+//! in the last half of the allowed length.
+
+use crate::profiles::Profile;
+use crate::providers;
+use pgrx::prelude::*;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Encoding};
+use tiktoken_rs::CoreBPE;
+
+/// Context windows of well-known models, in tokens, by model name prefix. The
+/// first matching prefix wins.
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("chatgpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4.5", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-vision", 128_000),
+    ("gpt-4-32k", 32_768),
+    // The original gpt-4 and its snapshots; newer variants are listed above.
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo-instruct", 4_096),
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-35-turbo", 16_385),
+    ("o1-mini", 128_000),
+    ("o1-preview", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("claude-", 200_000),
+    ("anthropic.claude-", 200_000),
+    ("gemini-1.0", 32_760),
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini-", 1_048_576),
+];
+
+/// Number of tokens in `text` for `model`, or the configured model if NULL.
+/// `model` may also name an encoding, such as `o200k_base`. NULL if `text` is.
+#[pg_extern(stable, parallel_safe)]
+fn summarize_token_count(text: Option<&str>, model: default!(Option<&str>, "NULL")) -> Option<i64> {
+    let text = text?;
+    let model = model.map(str::to_string).unwrap_or_else(|| {
+        let profile = Profile::configured(None).unwrap_or_else(|e| e.report());
+        profile.model.unwrap_or_else(|| {
+            providers::build(profile.provider, None, "")
+                .unwrap_or_else(|e| e.report())
+                .default_model()
+                .to_string()
+        })
+    });
+    Some(Tokenizer::for_model(&model).count(text) as i64)
+}
+
+/// Context window of `model` in tokens, prompt and answer included, if known.
+pub fn context_window(model: &str) -> Option<usize> {
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|&(_, window)| window)
+}
+
+/// A BPE encoding, and the ways input text is measured and cut with it.
+#[derive(Clone, Copy)]
+pub struct Tokenizer(&'static CoreBPE);
+
+impl Tokenizer {
+    /// The encoding of `model`, which may also be the name of an encoding.
+    /// Models it does not know are counted with cl100k_base.
+    pub fn for_model(model: &str) -> Self {
+        let encoding = match model {
+            "o200k_base" => Encoding::O200kBase,
+            "cl100k_base" => Encoding::Cl100kBase,
+            "p50k_base" => Encoding::P50kBase,
+            "r50k_base" => Encoding::R50kBase,
+            _ => get_tokenizer(model).unwrap_or(Encoding::Cl100kBase),
+        };
+        // Each encoding is loaded once per backend, on first use.
+        Tokenizer(match encoding {
+            Encoding::O200kBase => tiktoken_rs::o200k_base_singleton(),
+            Encoding::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Encoding::P50kBase => tiktoken_rs::p50k_base_singleton(),
+            Encoding::P50kEdit => tiktoken_rs::p50k_edit_singleton(),
+            Encoding::R50kBase | Encoding::Gpt2 => tiktoken_rs::r50k_base_singleton(),
+        })
+    }
+
+    /// Number of tokens in `text`.
+    pub fn count(&self, text: &str) -> usize {
+        self.0.encode_ordinary(text).len()
+    }
+
+    /// The longest prefix of `text` of at most `max_tokens` tokens, cut at a
+    /// boundary where possible.
+    pub fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        &text[..self.prefix_len(text, max_tokens)]
+    }
+
+    /// Cuts `text` into consecutive chunks of at most `max_tokens` tokens,
+    /// each repeating about the last `overlap` tokens of the one before it.
+    pub fn split<'a>(&self, text: &'a str, max_tokens: usize, overlap: usize) -> Vec<&'a str> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        loop {
+            let end = start + self.prefix_len(&text[start..], max_tokens);
+            chunks.push(&text[start..end]);
+            if end == text.len() {
+                return chunks;
+            }
+            start += self.next_start(&text[start..], end - start, overlap);
+        }
+    }
+
+    /// Offset in `text` where the chunk after `text[..end]` starts, repeating
+    /// about its last `overlap` tokens.
+    pub fn next_start(&self, text: &str, end: usize, overlap: usize) -> usize {
+        match self.overlap_start(&text[..end], overlap) {
+            0 => end,
+            start => start,
+        }
+    }
+
+    /// Byte length of the prefix [`Tokenizer::truncate`] returns; never zero
+    /// for a non-empty `text`, so that cutting always makes progress.
+    pub fn prefix_len(&self, text: &str, max_tokens: usize) -> usize {
+        // Encode a window that grows until it no longer fits, so a long text
+        // is not encoded whole for every chunk.
+        let mut chars = max_tokens.max(1) * 8;
+        let (window, tokens) = loop {
+            let end = text
+                .char_indices()
+                .nth(chars)
+                .map_or(text.len(), |(i, _)| i);
+            let tokens = self.0.encode_ordinary(&text[..end]);
+            if tokens.len() > max_tokens {
+                break (&text[..end], tokens);
+            }
+            if end == text.len() {
+                return text.len();
+            }
+            chars *= 2;
+        };
+
+        // The longest prefix that fits, cut anywhere: the bytes of its first
+        // tokens, without a character they end inside of.
+        let mut hard_end = self.byte_len(&tokens[..max_tokens]);
+        while !window.is_char_boundary(hard_end) {
+            hard_end -= 1;
+        }
+        if hard_end == 0 {
+            return window.chars().next().map_or(0, char::len_utf8);
+        }
+
+        let head = &text[..hard_end];
+        let soft_end = ["\n\n", ". ", "! ", "? ", "\n", " "]
+            .into_iter()
+            .find_map(|separator| {
+                head.rfind(separator)
+                    .map(|i| i + separator.len())
+                    .filter(|&end| end > hard_end / 2)
+            });
+        soft_end.unwrap_or(hard_end)
+    }
+
+    /// Offset in `chunk` where its last `overlap` tokens start, moved forward
+    /// to the next word.
+    fn overlap_start(&self, chunk: &str, overlap: usize) -> usize {
+        if overlap == 0 {
+            return chunk.len();
+        }
+        let tokens = self.0.encode_ordinary(chunk);
+        let Some(kept) = tokens.len().checked_sub(overlap).filter(|&kept| kept > 0) else {
+            return 0;
+        };
+        let mut start = self.byte_len(&tokens[..kept]);
+        while !chunk.is_char_boundary(start) {
+            start += 1;
+        }
+        chunk[start..]
+            .char_indices()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(start, |(i, c)| start + i + c.len_utf8())
+    }
+
+    /// Number of bytes of the longest run of `tokens` from the start that
+    /// decodes to whole characters; tokens can end inside of a character.
+    fn byte_len(&self, tokens: &[tiktoken_rs::Rank]) -> usize {
+        (0..=tokens.len())
+            .rev()
+            .find_map(|end| self.0.decode(tokens[..end].to_vec()).ok())
+            .map_or(0, |text| text.len())
+    }
+}
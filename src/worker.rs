@@ -0,0 +1,58 @@
+//! Background workers that drain `summarize_jobs`.
+//!
+//! `pg_summarizer.workers` workers are started when the extension is loaded
+//! through `shared_preload_libraries`. Each connects to
+//! `pg_summarizer.database` and runs queued jobs one transaction at a time,
+//! sleeping for `pg_summarizer.worker_naptime` once the queue is empty. The
+//! first worker also polls OpenAI batches, see [`crate::openai_batch`].
+
+use crate::{guc, jobs, openai_batch};
+use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
+use pgrx::prelude::*;
+use std::time::{Duration, Instant};
+
+pub fn register() {
+    let preloading = unsafe { pg_sys::process_shared_preload_libraries_in_progress };
+    if !preloading {
+        return;
+    }
+    for i in 0..guc::WORKERS.get() {
+        BackgroundWorkerBuilder::new(&format!("pg_summarize worker {}", i + 1))
+            .set_type("pg_summarize worker")
+            .set_function("pg_summarize_worker_main")
+            .set_library("pg_summarize")
+            .set_argument(i.into_datum())
+            .enable_spi_access()
+            .set_restart_time(Some(Duration::from_secs(10)))
+            .load();
+    }
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_summarize_worker_main(arg: pg_sys::Datum) {
+    let index = unsafe { i32::from_datum(arg, false) }.unwrap_or_default();
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    let database = guc::DATABASE
+        .get()
+        .unwrap_or_else(|| "postgres".to_string());
+    BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+
+    let naptime = || Duration::from_millis(guc::WORKER_NAPTIME.get() as u64);
+    let poll_interval = || Duration::from_secs(guc::OPENAI_BATCH_POLL_INTERVAL.get() as u64);
+    let mut last_poll: Option<Instant> = None;
+    while BackgroundWorker::wait_latch(Some(naptime())) {
+        if !BackgroundWorker::transaction(jobs::queue_exists) {
+            continue;
+        }
+        while BackgroundWorker::transaction(jobs::run_next_job) {
+            if BackgroundWorker::sigterm_received() {
+                return;
+            }
+        }
+        if index == 0 && last_poll.is_none_or(|at| at.elapsed() >= poll_interval()) {
+            BackgroundWorker::transaction(openai_batch::poll);
+            last_poll = Some(Instant::now());
+        }
+    }
+}